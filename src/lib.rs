@@ -0,0 +1,29 @@
+//! Tunnels TCP connections through HTTPS (CONNECT) or SOCKS5 proxy
+
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+#[macro_use]
+extern crate clap;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate quick_error;
+extern crate url;
+#[macro_use]
+extern crate futures;
+extern crate tokio;
+extern crate tokio_io;
+#[cfg(feature = "dns")]
+extern crate tokio_dns;
+extern crate data_encoding;
+extern crate md5;
+extern crate native_tls;
+extern crate tokio_tls;
+extern crate socket2;
+extern crate toml;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+
+pub mod config;
+pub mod proxy;
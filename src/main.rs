@@ -1,28 +1,16 @@
 #[macro_use]
 extern crate log;
-extern crate env_logger;
-#[macro_use]
-extern crate clap;
-#[macro_use]
-extern crate lazy_static;
-#[macro_use]
-extern crate quick_error;
-extern crate url;
 extern crate futures;
 extern crate tokio;
-#[macro_use]
-extern crate tokio_io;
-extern crate tokio_dns;
-extern crate data_encoding;
-
-mod config;
-mod proxy;
+extern crate tokio_signal;
+extern crate ptunnel;
 
-use config::{parse_args};
-use proxy::{run_tunnel};
+use ptunnel::config::parse_args;
+use ptunnel::proxy::{check_tunnels, drain, log_totals, run_tunnel, serve_metrics, supervise};
 use std::process::exit;
 use std::io::{self, Write};
-use futures::{future, Future};
+use futures::{future, Future, Stream};
+use futures::sync::oneshot;
 
 fn main() {
     
@@ -35,28 +23,94 @@ fn main() {
     };
     debug!("Started with following config {:?}", config);
 
-    let user_encoded = config.user.map(|u| u.encoded());
-    let mut servers: Box<Future<Item=(), Error=std::io::Error>+Send> = Box::new(future::ok(()));
+    if config.check {
+        let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let failed = rt.block_on(check_tunnels(config.tunnels, config.proxy)).unwrap_or(1);
+        exit(if failed > 0 { 1 } else { 0 })
+    }
+
+    let (shutdown_tx, shutdown) = oneshot::channel::<()>();
+    let shutdown = shutdown.shared();
+
+    let mut servers = vec![];
     for t in config.tunnels {
         debug!("Staring tunnel {}:{:?} on ", config.local_addr,t);
-        let server = run_tunnel(
-                config.local_addr.clone(), 
-                t, 
-                config.proxy.clone(),
-                user_encoded.clone());
-        servers = Box::new(servers.join(server).map(|_| ()));
+        let name = t.to_string();
+        let server = match config.supervise {
+                Some(backoff) => supervise(config.local_addr, t, config.proxy.clone(), shutdown.clone(), backoff),
+                None => run_tunnel(config.local_addr, t, config.proxy.clone(), shutdown.clone()),
+            }
+            // failure of one tunnel should not stop others
+            .then(move |res| {
+                if let Err(e) = res {
+                    error!("Tunnel {} failed: {}", name, e);
+                }
+                Ok::<_, ()>(())
+            });
+        servers.push(server);
     }
 
-    let servers = servers.map_err(|e| error!("Error in proxy connection {}", e));
-    
+    let servers = future::join_all(servers).map(|_| ());
+    let servers: Box<dyn Future<Item=(), Error=()>+Send> = match config.stats_interval {
+        Some(interval) => {
+            // logging runs while servers are alive and its failure should not stop them
+            let stats = log_totals(interval)
+                .map_err(|e| error!("Statistics logging failed: {}", e))
+                .then(|_| future::empty());
+            Box::new(servers.select(stats).map(|_| ()).map_err(|_| ()))
+        }
+        None => Box::new(servers),
+    };
+    let servers: Box<dyn Future<Item=(), Error=()>+Send> = match config.metrics_addr {
+        Some(addr) => {
+            let metrics = serve_metrics(addr)
+                .map_err(|e| error!("Metrics endpoint failed: {}", e))
+                .then(|_| future::empty());
+            Box::new(servers.select(metrics).map(|_| ()).map_err(|_| ()))
+        }
+        None => servers,
+    };
+
+    // first interrupt stops accepting connections, second one terminates immediately
+    let mut shutdown_tx = Some(shutdown_tx);
+    let interrupt = tokio_signal::ctrl_c()
+        .flatten_stream()
+        .for_each(move |_| {
+            match shutdown_tx.take() {
+                Some(tx) => {
+                    info!("Interrupted, shutting down");
+                    let _ = tx.send(());
+                }
+                None => {
+                    warn!("Interrupted again, terminating");
+                    exit(1)
+                }
+            }
+            Ok(())
+        })
+        .map_err(|e| error!("Cannot handle interrupt signal: {}", e));
+
+    let grace = config.shutdown_grace;
+    let main = future::lazy(move || {
+        tokio::spawn(interrupt);
+        servers
+    }).then(move |_| -> Box<dyn Future<Item=(), Error=()>+Send> {
+        match grace {
+            Some(grace) => Box::new(drain(grace).map_err(|e| error!("Shutdown failed: {}", e))),
+            None => Box::new(future::ok(())),
+        }
+    });
+
+    // remaining connections are dropped with runtime, once main future is done
     if config.multithreaded {
         debug!("Running in thread pool");
-        tokio::run(servers);
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let _ = rt.block_on(main);
+        rt.shutdown_now().wait().unwrap();
     } else {
         debug!("Running in current thread");
         let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
-        let _ = rt.block_on(servers); //ignore error
-        
+        let _ = rt.block_on(main); //ignore error
     }
 
 }
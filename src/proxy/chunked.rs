@@ -0,0 +1,169 @@
+use futures::{Async, Future, Poll};
+use std::cmp::min;
+use std::io::{self, Read};
+use super::error::ProxyError;
+
+/// Longest chunk size or trailer line accepted
+const MAX_LINE: usize = 1024;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum State {
+    Size,
+    Data(usize),
+    DataEnd,
+    Trailer,
+    Done,
+}
+
+/// Decoder of chunked transfer coding (RFC 7230 section 4.1), chunk extensions
+/// and trailers are skipped, bare LF is accepted as line ending
+pub struct Decoder {
+    state: State,
+    line: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder {
+            state: State::Size,
+            line: vec![],
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Most bytes next decode call can take, so that nothing after end of body is consumed
+    pub fn wanted(&self) -> usize {
+        match self.state {
+            State::Data(n) => n,
+            State::Done => 0,
+            _ => 1,
+        }
+    }
+
+    /// Decodes input not longer than wanted(), chunk data are appended to out
+    pub fn decode(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<(), ProxyError> {
+        assert!(input.len() <= self.wanted());
+        if let State::Data(n) = self.state {
+            out.extend_from_slice(input);
+            self.state = if n == input.len() { State::DataEnd } else { State::Data(n - input.len()) };
+            return Ok(());
+        }
+        for &b in input {
+            match (self.state, b) {
+                (State::Size, b'\n') => {
+                    let size = parse_size(&self.line)?;
+                    self.line.clear();
+                    self.state = if size == 0 { State::Trailer } else { State::Data(size) };
+                }
+                (State::Trailer, b'\n') => {
+                    if self.line.is_empty() {
+                        self.state = State::Done;
+                    }
+                    self.line.clear();
+                }
+                (State::Size, b'\r') | (State::Trailer, b'\r') | (State::DataEnd, b'\r') => (),
+                (State::Size, b) | (State::Trailer, b) => {
+                    if self.line.len() >= MAX_LINE {
+                        return Err(ProxyError::InvalidChunkedBody);
+                    }
+                    self.line.push(b)
+                }
+                (State::DataEnd, b'\n') => self.state = State::Size,
+                (State::DataEnd, _) => return Err(ProxyError::InvalidChunkedBody),
+                (State::Data(_), _) | (State::Done, _) => unreachable!("input longer than wanted"),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_size(line: &[u8]) -> Result<usize, ProxyError> {
+    let line = String::from_utf8_lossy(line);
+    let size = line.split(';').next().unwrap_or_default().trim();
+    usize::from_str_radix(size, 16).map_err(|_| ProxyError::InvalidChunkedBody)
+}
+
+/// Reads whole chunked body and resolves to reader positioned right after it,
+/// fails when body is invalid or longer than limit (counting also chunk headers)
+pub struct Drain<R> {
+    reader: Option<R>,
+    decoder: Decoder,
+    read: usize,
+    limit: usize,
+}
+
+pub fn drain<R: Read>(reader: R, limit: usize) -> Drain<R> {
+    Drain {
+        reader: Some(reader),
+        decoder: Decoder::new(),
+        read: 0,
+        limit,
+    }
+}
+
+impl<R: Read> Future for Drain<R> {
+    type Item = R;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<R, io::Error> {
+        let mut buf = [0u8; 1024];
+        // chunk data are not kept
+        let mut discarded = vec![];
+        while !self.decoder.is_done() {
+            let max = min(min(self.decoder.wanted(), buf.len()), self.limit - self.read);
+            if max == 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Chunked body too long"));
+            }
+            let n = match self.reader.as_mut().unwrap().read(&mut buf[..max]) {
+                Ok(0) => return Err(ProxyError::Truncated("chunked body").into()),
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(e),
+            };
+            self.read += n;
+            self.decoder.decode(&buf[..n], &mut discarded)?;
+            discarded.clear();
+        }
+        Ok(Async::Ready(self.reader.take().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(body: &[u8]) -> Result<Vec<u8>, ProxyError> {
+        let mut d = Decoder::new();
+        let mut out = vec![];
+        let mut rest = body;
+        while !d.is_done() && !rest.is_empty() {
+            let n = min(d.wanted(), rest.len());
+            d.decode(&rest[..n], &mut out)?;
+            rest = &rest[n..];
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(b"4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\nX: y\r\n\r\n").unwrap(), b"Wikipedia");
+        assert_eq!(decode(b"3\nabc\n0\n\n").unwrap(), b"abc");
+        assert_eq!(decode(b"zz\r\n"), Err(ProxyError::InvalidChunkedBody));
+        assert_eq!(decode(b"1\r\nab\r\n"), Err(ProxyError::InvalidChunkedBody));
+        assert_eq!(decode(&[b'1'; 2000]), Err(ProxyError::InvalidChunkedBody));
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut r = drain(&b"4\r\nWiki\r\n0\r\n\r\nNEXT"[..], 100).wait().unwrap();
+        let mut rest = String::new();
+        r.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "NEXT");
+        assert!(drain(&b"4\r\nWiki\r\n0\r\n\r\n"[..], 10).wait().is_err());
+        let e = drain(&b"4\r\nWi"[..], 100).wait().unwrap_err();
+        assert_eq!(ProxyError::from_io(&e), Some(&ProxyError::Truncated("chunked body")));
+    }
+}
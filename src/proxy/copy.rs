@@ -0,0 +1,165 @@
+use futures::{Future, Poll};
+use std::fmt;
+use std::io;
+use tokio_io::{AsyncRead, AsyncWrite};
+use std::sync::Arc;
+use super::limit::RateLimiter;
+use super::stats::{Direction, ThroughputSampler};
+
+/// Buffer size used when tunnel does not set one (same as tokio_io::io::copy)
+pub const DEFAULT_BUFFER_SIZE: usize = 2048;
+
+/// Why tunneled connection ended, for the log line at its end
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloseReason {
+    /// Client closed its side first
+    LocalEof,
+    /// Remote end closed its side first
+    RemoteEof,
+    IdleTimeout,
+    Error,
+    /// Connection was still open, when runtime was stopped
+    Shutdown,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            CloseReason::LocalEof => "client closed connection",
+            CloseReason::RemoteEof => "remote end closed connection",
+            CloseReason::IdleTimeout => "idle timeout",
+            CloseReason::Error => "error",
+            CloseReason::Shutdown => "shutdown",
+        })
+    }
+}
+
+/// Same as tokio_io::io::Copy, but with buffer of given size, optional rate limit
+/// and optional sampling of throughput
+pub struct Copy<R, W> {
+    reader: Option<R>,
+    read_done: bool,
+    writer: Option<W>,
+    pos: usize,
+    cap: usize,
+    amt: u64,
+    buf: Box<[u8]>,
+    limiter: Option<RateLimiter>,
+    sampler: Option<(Arc<ThroughputSampler>, Direction)>,
+}
+
+/// Copies all bytes from reader to writer, resolves to number of bytes copied
+pub fn copy<R, W>(
+    reader: R,
+    writer: W,
+    buffer_size: usize,
+    limiter: Option<RateLimiter>,
+    sampler: Option<(Arc<ThroughputSampler>, Direction)>,
+) -> Copy<R, W>
+where
+    R: AsyncRead,
+    W: AsyncWrite,
+{
+    Copy {
+        reader: Some(reader),
+        read_done: false,
+        writer: Some(writer),
+        amt: 0,
+        pos: 0,
+        cap: 0,
+        buf: vec![0; buffer_size].into_boxed_slice(),
+        limiter,
+        sampler,
+    }
+}
+
+impl<R, W> Copy<R, W> {
+    /// Bytes written to writer so far
+    pub fn copied(&self) -> u64 {
+        self.amt
+    }
+
+    /// True if all data read so far were written to writer
+    pub fn is_flushed(&self) -> bool {
+        self.pos == self.cap
+    }
+}
+
+impl<R, W> Future for Copy<R, W>
+where
+    R: AsyncRead,
+    W: AsyncWrite,
+{
+    type Item = (u64, R, W);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(u64, R, W), io::Error> {
+        loop {
+            if self.pos == self.cap && !self.read_done {
+                let max = match self.limiter {
+                    Some(ref mut l) => try_ready!(l.poll_acquire(self.buf.len())),
+                    None => self.buf.len(),
+                };
+                let reader = self.reader.as_mut().unwrap();
+                let n = try_ready!(reader.poll_read(&mut self.buf[..max]));
+                if let Some(ref mut l) = self.limiter {
+                    l.consume(n);
+                }
+                if n == 0 {
+                    self.read_done = true;
+                } else {
+                    self.pos = 0;
+                    self.cap = n;
+                }
+            }
+
+            while self.pos < self.cap {
+                let writer = self.writer.as_mut().unwrap();
+                let i = try_ready!(writer.poll_write(&self.buf[self.pos..self.cap]));
+                if i == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "write zero byte into writer",
+                    ));
+                }
+                self.pos += i;
+                self.amt += i as u64;
+                if let Some((ref s, direction)) = self.sampler {
+                    s.record(direction, i);
+                }
+            }
+
+            if self.pos == self.cap && self.read_done {
+                try_ready!(self.writer.as_mut().unwrap().poll_flush());
+                let reader = self.reader.take().unwrap();
+                let writer = self.writer.take().unwrap();
+                return Ok((self.amt, reader, writer).into());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy() {
+        let data: Vec<u8> = (0..10000u32).map(|i| i as u8).collect();
+        let (n, _, out) = copy(&data[..], io::Cursor::new(vec![]), 7, None, None).wait().unwrap();
+        assert_eq!(n, 10000);
+        assert_eq!(out.into_inner(), data);
+    }
+
+    #[test]
+    fn test_copy_limited() {
+        let data = vec![1u8; 1500];
+        let start = ::std::time::Instant::now();
+        let f = copy(&data[..], io::Cursor::new(vec![]), 1024, Some(RateLimiter::new(1000)), None);
+        let mut rt = ::tokio::runtime::current_thread::Runtime::new().unwrap();
+        let (n, _, _) = rt.block_on(f).unwrap();
+        assert_eq!(n, 1500);
+        // first 1000 bytes are sent immediately, rest after half a second
+        assert!(start.elapsed() >= ::std::time::Duration::from_millis(450));
+    }
+}
@@ -0,0 +1,296 @@
+use md5;
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use super::stream::Headers;
+
+/// Digest challenge (RFC 2617) from Proxy-Authenticate header
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Challenge {
+    pub realm: String,
+    pub nonce: String,
+    pub opaque: Option<String>,
+    pub algorithm: Option<String>,
+    /// true if proxy offered qop=auth, other qop values are not supported
+    pub qop_auth: bool,
+}
+
+impl Challenge {
+    /// Parses one challenge of Proxy-Authenticate header, None if it is not supported Digest challenge
+    pub fn parse(value: &str) -> Option<Challenge> {
+        let (scheme, params) = value.trim_start().split_once(char::is_whitespace).unwrap_or((value.trim(), ""));
+        if !scheme.eq_ignore_ascii_case("digest") {
+            return None;
+        }
+        let mut c = Challenge::default();
+        let mut has_nonce = false;
+        let mut qop_offered = false;
+        for (name, v) in parse_params(params) {
+            match name.to_ascii_lowercase().as_str() {
+                "realm" => c.realm = v,
+                "nonce" => {
+                    c.nonce = v;
+                    has_nonce = true
+                }
+                "opaque" => c.opaque = Some(v),
+                "algorithm" => c.algorithm = Some(v),
+                "qop" => {
+                    qop_offered = true;
+                    c.qop_auth = v.split(',').any(|q| q.trim().eq_ignore_ascii_case("auth"))
+                }
+                _ => (),
+            }
+        }
+        let algorithm_ok = match c.algorithm {
+            None => true,
+            Some(ref a) => a.eq_ignore_ascii_case("md5") || a.eq_ignore_ascii_case("md5-sess"),
+        };
+        if !has_nonce || !algorithm_ok || (qop_offered && !c.qop_auth) {
+            return None;
+        }
+        Some(c)
+    }
+
+    /// Strongest supported Digest challenge in proxy response headers - with qop=auth
+    /// over without it, then MD5-sess over MD5, first one of equally strong
+    pub fn from_headers(headers: &Headers) -> Option<Challenge> {
+        headers
+            .iter()
+            .filter(|&(name, _)| name.eq_ignore_ascii_case("proxy-authenticate"))
+            .flat_map(|(_, value)| split_challenges(value))
+            .filter_map(Challenge::parse)
+            .min_by_key(|c| Reverse((c.qop_auth, c.is_sess())))
+    }
+
+    fn is_sess(&self) -> bool {
+        self.algorithm
+            .as_ref()
+            .map(|a| a.eq_ignore_ascii_case("md5-sess"))
+            .unwrap_or(false)
+    }
+
+    /// Computes request digest, nonce count is always 1 as challenge is used just once
+    fn response(&self, method: &str, uri: &str, user: &str, password: &str, cnonce: &str) -> String {
+        let mut ha1 = hex_md5(&format!("{}:{}:{}", user, self.realm, password));
+        if self.is_sess() {
+            ha1 = hex_md5(&format!("{}:{}:{}", ha1, self.nonce, cnonce));
+        }
+        let ha2 = hex_md5(&format!("{}:{}", method, uri));
+        if self.qop_auth {
+            hex_md5(&format!("{}:{}:{}:{}:auth:{}", ha1, self.nonce, NONCE_COUNT, cnonce, ha2))
+        } else {
+            hex_md5(&format!("{}:{}:{}", ha1, self.nonce, ha2))
+        }
+    }
+
+    /// Value of Proxy-Authorization header answering this challenge
+    pub fn authorization(&self, method: &str, uri: &str, user: &str, password: &str) -> String {
+        self.authorization_with_cnonce(method, uri, user, password, &new_cnonce())
+    }
+
+    fn authorization_with_cnonce(&self, method: &str, uri: &str, user: &str, password: &str, cnonce: &str) -> String {
+        let mut s = format!(
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", response=\"{}\"",
+            quote(user),
+            quote(&self.realm),
+            quote(&self.nonce),
+            quote(uri),
+            self.response(method, uri, user, password, cnonce)
+        );
+        if let Some(ref a) = self.algorithm {
+            s.push_str(&format!(", algorithm={}", a));
+        }
+        if let Some(ref o) = self.opaque {
+            s.push_str(&format!(", opaque=\"{}\"", quote(o)));
+        }
+        if self.qop_auth {
+            s.push_str(&format!(", qop=auth, nc={}, cnonce=\"{}\"", NONCE_COUNT, cnonce));
+        }
+        s
+    }
+}
+
+const NONCE_COUNT: &str = "00000001";
+
+fn hex_md5(s: &str) -> String {
+    format!("{:x}", md5::compute(s.as_bytes()))
+}
+
+fn quote(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Client nonce - does not need to be cryptographically strong, just unique
+fn new_cnonce() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    hex_md5(&format!("{}:{}", now, n))[..16].to_owned()
+}
+
+/// Splits value of Proxy-Authenticate header into challenges - one header can carry
+/// several of them, separated by commas like their parameters. Challenge starts with
+/// scheme name, which (unlike parameter) is not followed by `=`.
+pub fn split_challenges(value: &str) -> Vec<&str> {
+    let mut challenges = vec![];
+    let mut start = 0;
+    let mut item_start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in value.char_indices().chain(Some((value.len(), ','))) {
+        if quoted {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => quoted = false,
+                _ => (),
+            }
+            continue;
+        }
+        match c {
+            '"' => quoted = true,
+            ',' => {
+                if item_start > start && is_scheme(&value[item_start..i]) {
+                    challenges.push(&value[start..item_start]);
+                    start = item_start;
+                }
+                item_start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    challenges.push(&value[start..]);
+    challenges
+        .into_iter()
+        .map(|c| c.trim_matches(|c: char| c == ',' || c.is_whitespace()))
+        .filter(|c| !c.is_empty())
+        .collect()
+}
+
+/// True if comma separated item starts with scheme name, not with parameter name
+fn is_scheme(item: &str) -> bool {
+    let item = item.trim_start();
+    let name_end = item.find(|c: char| c == '=' || c.is_whitespace()).unwrap_or(item.len());
+    name_end > 0 && !item[name_end..].trim_start().starts_with('=')
+}
+
+/// Parses comma separated name=value pairs, values can be quoted strings
+fn parse_params(s: &str) -> Vec<(String, String)> {
+    let mut params = vec![];
+    let mut chars = s.chars().peekable();
+    loop {
+        while let Some(&c) = chars.peek() {
+            if c == ',' || c.is_whitespace() {
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        let mut name = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == '=' || c == ',' {
+                break;
+            }
+            name.push(c);
+            chars.next();
+        }
+        if name.is_empty() {
+            break;
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'=') {
+            chars.next();
+            if chars.peek() == Some(&'"') {
+                chars.next();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next()),
+                        '"' => break,
+                        c => value.push(c),
+                    }
+                }
+            } else {
+                while let Some(&c) = chars.peek() {
+                    if c == ',' {
+                        break;
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+            }
+        }
+        params.push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_challenge() {
+        let c = Challenge::parse(
+            "Digest realm=\"testrealm@host.com\", qop=\"auth,auth-int\", \
+             nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", opaque=\"5ccc069c403ebaf9f0171e9517f40e41\"",
+        )
+        .unwrap();
+        assert_eq!(c.realm, "testrealm@host.com");
+        assert_eq!(c.nonce, "dcd98b7102dd2f0e8b11d0f600bfb0c093");
+        assert_eq!(c.opaque.as_deref(), Some("5ccc069c403ebaf9f0171e9517f40e41"));
+        assert!(c.qop_auth);
+        assert!(Challenge::parse("Basic realm=\"x\"").is_none());
+        assert!(Challenge::parse("Digest realm=\"x\"").is_none());
+        assert!(Challenge::parse("Digest nonce=\"n\", qop=\"auth-int\"").is_none());
+        assert!(Challenge::parse("Digest nonce=\"n\", algorithm=SHA-256").is_none());
+        assert_eq!(Challenge::parse("digest nonce=\"a\\\"b\"").unwrap().nonce, "a\"b");
+        // multi-byte characters around scheme name
+        assert!(Challenge::parse("Digesté nonce=\"n\"").is_none());
+        assert!(Challenge::parse("ééé nonce=\"n\"").is_none());
+        assert!(Challenge::parse("é").is_none());
+        assert_eq!(Challenge::parse("Digest realm=\"café\", nonce=\"n\"").unwrap().realm, "café");
+    }
+
+    #[test]
+    fn test_split_challenges() {
+        assert_eq!(
+            split_challenges("Basic realm=\"a, b\", Digest realm = \"x\", nonce=\"n\", qop=\"auth,auth-int\", NTLM"),
+            vec!["Basic realm=\"a, b\"", "Digest realm = \"x\", nonce=\"n\", qop=\"auth,auth-int\"", "NTLM"]
+        );
+        assert_eq!(split_challenges("Negotiate YII=, , Basic"), vec!["Negotiate YII=", "Basic"]);
+        assert_eq!(split_challenges("Digest nonce=\"a\\\",Basic\""), vec!["Digest nonce=\"a\\\",Basic\""]);
+        assert!(split_challenges(" ,").is_empty());
+
+        let headers = vec![
+            ("Proxy-Authenticate".to_owned(), "Basic realm=\"x\", Digest realm=\"x\", nonce=\"weak\"".to_owned()),
+            ("Proxy-Authenticate".to_owned(),
+                "Digest nonce=\"n\", algorithm=SHA-256, Digest nonce=\"strong\", qop=\"auth\"".to_owned()),
+        ];
+        assert_eq!(Challenge::from_headers(&headers).unwrap().nonce, "strong");
+        assert_eq!(Challenge::from_headers(&headers[..1].to_vec()).unwrap().nonce, "weak");
+    }
+
+    #[test]
+    fn test_digest_response() {
+        // example from RFC 2617
+        let c = Challenge {
+            realm: "testrealm@host.com".into(),
+            nonce: "dcd98b7102dd2f0e8b11d0f600bfb0c093".into(),
+            opaque: Some("5ccc069c403ebaf9f0171e9517f40e41".into()),
+            algorithm: None,
+            qop_auth: true,
+        };
+        assert_eq!(
+            c.response("GET", "/dir/index.html", "Mufasa", "Circle Of Life", "0a4f113b"),
+            "6629fae49393a05397450978507c4ef1"
+        );
+        let h = c.authorization_with_cnonce("GET", "/dir/index.html", "Mufasa", "Circle Of Life", "0a4f113b");
+        assert!(h.starts_with("Digest username=\"Mufasa\", realm=\"testrealm@host.com\""));
+        assert!(h.contains("response=\"6629fae49393a05397450978507c4ef1\""));
+        assert!(h.ends_with("qop=auth, nc=00000001, cnonce=\"0a4f113b\""));
+        assert_ne!(new_cnonce(), new_cnonce());
+    }
+}
@@ -0,0 +1,147 @@
+use futures::{future, Future};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::timer::Timeout;
+#[cfg(feature = "dns")]
+use tokio_dns::resolve_sock_addr;
+use tokio_io::IoFuture;
+use super::stats;
+
+/// Failed resolutions are cached at most for this time, so that temporary DNS problem does not stick
+pub const MAX_NEGATIVE_TTL: Duration = Duration::from_secs(5);
+
+type Key = (String, u16);
+type CachedResult = Result<Vec<SocketAddr>, (IoErrorKind, String)>;
+
+struct Entry {
+    result: CachedResult,
+    expires: Instant,
+}
+
+lazy_static! {
+    static ref CACHE: Mutex<HashMap<Key, Entry>> = Mutex::new(HashMap::new());
+}
+
+/// Resolves host for direct connection, within timeout if set. Results are cached for ttl
+/// if set - system resolver does not tell record TTL, so the same ttl is used for all hosts
+pub fn resolve(host: &str, port: u16, timeout: Option<Duration>, ttl: Option<Duration>) -> IoFuture<Vec<SocketAddr>> {
+    let key = (host.to_ascii_lowercase(), port);
+    if ttl.is_some() {
+        let cached = lookup(&key, Instant::now());
+        stats::count_dns(cached.is_some());
+        if let Some(r) = cached {
+            debug!("Resolved {}:{} from DNS cache", host, port);
+            return Box::new(future::result(r.map_err(|(kind, msg)| IoError::new(kind, msg))));
+        }
+    }
+    let f = resolve_host(host, port);
+    let f = match timeout {
+        Some(t) => {
+            let name = host.to_owned();
+            Box::new(Timeout::new(f, t).map_err(move |e| {
+                if e.is_elapsed() {
+                    IoError::new(IoErrorKind::TimedOut, format!("Resolution of {} timed out", name))
+                } else if e.is_inner() {
+                    e.into_inner().unwrap()
+                } else {
+                    IoError::other(e.into_timer().unwrap())
+                }
+            }))
+        }
+        None => f,
+    };
+    match ttl {
+        Some(ttl) => Box::new(f.then(move |r| {
+            let cached = match r {
+                Ok(ref addrs) => Ok(addrs.clone()),
+                Err(ref e) => Err((e.kind(), e.to_string())),
+            };
+            store(key, cached, ttl, Instant::now());
+            r
+        })),
+        None => f,
+    }
+}
+
+/// Resolves host with system resolver, IP address is used as is
+#[cfg(feature = "dns")]
+pub fn resolve_host(host: &str, port: u16) -> IoFuture<Vec<SocketAddr>> {
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Box::new(future::ok(vec![SocketAddr::new(ip, port)])),
+        Err(_) => resolve_sock_addr((host, port)),
+    }
+}
+
+/// Built without DNS support, so only IP address can be used
+#[cfg(not(feature = "dns"))]
+pub fn resolve_host(host: &str, port: u16) -> IoFuture<Vec<SocketAddr>> {
+    Box::new(future::result(host.parse::<IpAddr>().map(|ip| vec![SocketAddr::new(ip, port)]).map_err(|_| {
+        IoError::new(
+            IoErrorKind::InvalidInput,
+            format!("Cannot resolve host name {}, ptunnel was built without DNS support (use IP address)", host),
+        )
+    })))
+}
+
+fn lookup(key: &Key, now: Instant) -> Option<CachedResult> {
+    let cache = CACHE.lock().unwrap();
+    cache
+        .get(key)
+        .filter(|e| e.expires > now)
+        .map(|e| e.result.clone())
+}
+
+fn store(key: Key, result: CachedResult, ttl: Duration, now: Instant) {
+    let ttl = if result.is_ok() { ttl } else { min(ttl, MAX_NEGATIVE_TTL) };
+    let mut cache = CACHE.lock().unwrap();
+    // expired entries are dropped here, so cache does not grow with hosts no longer used
+    cache.retain(|_, e| e.expires > now);
+    cache.insert(key, Entry { result, expires: now + ttl });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_expiry() {
+        let now = Instant::now();
+        let addrs = vec!["10.0.0.1:80".parse().unwrap()];
+        let key = ("cached.example.com".to_owned(), 80);
+        store(key.clone(), Ok(addrs.clone()), Duration::from_secs(60), now);
+        assert_eq!(lookup(&key, now + Duration::from_secs(59)), Some(Ok(addrs)));
+        assert_eq!(lookup(&key, now + Duration::from_secs(60)), None);
+        let key = ("failed.example.com".to_owned(), 80);
+        store(key.clone(), Err((IoErrorKind::Other, "failed".into())), Duration::from_secs(60), now);
+        assert!(lookup(&key, now + Duration::from_secs(4)).is_some());
+        assert_eq!(lookup(&key, now + MAX_NEGATIVE_TTL), None);
+    }
+
+    #[test]
+    fn test_resolve_host() {
+        let addrs = resolve_host("::1", 993).wait().unwrap();
+        assert_eq!(addrs, vec!["[::1]:993".parse().unwrap()]);
+        if !cfg!(feature = "dns") {
+            let e = resolve_host("imap.example.com", 993).wait().unwrap_err();
+            assert_eq!(e.kind(), IoErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn test_resolve_cached() {
+        let before = stats::totals();
+        let mut rt = ::tokio::runtime::current_thread::Runtime::new().unwrap();
+        let ttl = Some(Duration::from_secs(60));
+        let addrs = rt.block_on(resolve("127.0.0.1", 8123, None, ttl)).unwrap();
+        assert_eq!(addrs, vec!["127.0.0.1:8123".parse().unwrap()]);
+        let again = rt.block_on(resolve("127.0.0.1", 8123, Some(Duration::from_secs(1)), ttl)).unwrap();
+        assert_eq!(again, addrs);
+        let after = stats::totals();
+        assert!(after.dns_hits > before.dns_hits);
+        assert!(after.dns_misses > before.dns_misses);
+    }
+}
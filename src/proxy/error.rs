@@ -0,0 +1,187 @@
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+/// Authentication scheme requested by proxy in Proxy-Authenticate header
+#[derive(Debug, PartialEq, Clone)]
+pub enum AuthScheme {
+    Basic,
+    Digest,
+    Ntlm,
+    Other(String),
+}
+
+impl AuthScheme {
+    /// Parses scheme from challenge of Proxy-Authenticate header (first token)
+    pub fn from_challenge(value: &str) -> Option<AuthScheme> {
+        let name = value.split_whitespace().next()?;
+        Some(match name.to_ascii_lowercase().as_str() {
+            "basic" => AuthScheme::Basic,
+            "digest" => AuthScheme::Digest,
+            "ntlm" => AuthScheme::Ntlm,
+            _ => AuthScheme::Other(name.to_owned()),
+        })
+    }
+}
+
+impl fmt::Display for AuthScheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AuthScheme::Basic => write!(f, "Basic"),
+            AuthScheme::Digest => write!(f, "Digest"),
+            AuthScheme::Ntlm => write!(f, "NTLM"),
+            AuthScheme::Other(ref s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// Phase of connection via proxy, which took too long
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TimeoutPhase {
+    /// TCP connection to proxy
+    Connect,
+    /// Sending of CONNECT request
+    Request,
+    /// Waiting for status line and headers of response
+    Response,
+}
+
+impl fmt::Display for TimeoutPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            TimeoutPhase::Connect => "connect",
+            TimeoutPhase::Request => "request",
+            TimeoutPhase::Response => "response",
+        })
+    }
+}
+
+fn join_schemes(schemes: &[AuthScheme]) -> String {
+    schemes
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+quick_error! {
+#[derive(Debug, PartialEq, Clone)]
+pub enum ProxyError {
+    AuthRequired(schemes: Vec<AuthScheme>) {
+        display("Proxy authentication required (offered schemes: {})", join_schemes(schemes))
+    }
+    BadStatusLine {
+        display("Invalid status line")
+    }
+    NonUtf8Status {
+        display("Invalid status - not UTF8")
+    }
+    NonNumericStatus {
+        display("Invalid status - not number")
+    }
+    /// Error status with beginning of response body, which often explains the error
+    StatusNotSuccess(status: u16, body: String) {
+        display("Invalid status - {}{}", status, if body.is_empty() { "".to_owned() } else { format!(": {}", body) })
+    }
+    InvalidHeaderTermination {
+        display("Invalid end of line")
+    }
+    InvalidChunkedBody {
+        display("Invalid chunked body")
+    }
+    /// Proxy closed connection right after successful CONNECT, remote end is probably gone
+    UpstreamClosed {
+        display("Proxy closed connection right after CONNECT")
+    }
+    /// Remote port is not in allowed ports of proxy, nothing was sent
+    PortNotAllowed(port: u16) {
+        display("Connection to port {} is not allowed", port)
+    }
+    /// Data were waiting right after successful response to CONNECT, when none were expected
+    UnexpectedData {
+        display("Unexpected data after proxy response")
+    }
+    /// Token provider of proxy failed, so no request was sent
+    AuthUnavailable(reason: String) {
+        display("Proxy credentials are not available: {}", reason)
+    }
+    /// Proxy response (status line and headers) is longer than limit
+    HeadersTooLarge(limit: usize) {
+        display("Proxy response headers exceed {} bytes", limit)
+    }
+    /// Deadline of phase (set in proxy configuration) elapsed
+    Timeout { phase: TimeoutPhase } {
+        display("Proxy {} timed out", phase)
+    }
+    /// Proxy closed connection before complete response was received
+    Truncated(part: &'static str) {
+        display("truncated {}", part)
+    }
+}
+}
+
+impl ProxyError {
+    /// Extracts ProxyError from io::Error, if it was created from one
+    pub fn from_io(e: &IoError) -> Option<&ProxyError> {
+        e.get_ref().and_then(|inner| inner.downcast_ref::<ProxyError>())
+    }
+
+    fn kind(&self) -> IoErrorKind {
+        match *self {
+            ProxyError::AuthRequired(_) | ProxyError::PortNotAllowed(_) => IoErrorKind::PermissionDenied,
+            // kind is kept, so that connection closed by proxy can be retried
+            ProxyError::Truncated(_) => IoErrorKind::UnexpectedEof,
+            // token may be available later, so it is not permanent failure
+            ProxyError::StatusNotSuccess(..) | ProxyError::AuthUnavailable(_) => IoErrorKind::Other,
+            // transient as well, so that it can be retried
+            ProxyError::UpstreamClosed => IoErrorKind::ConnectionAborted,
+            ProxyError::Timeout { .. } => IoErrorKind::TimedOut,
+            ProxyError::BadStatusLine
+            | ProxyError::NonUtf8Status
+            | ProxyError::NonNumericStatus
+            | ProxyError::InvalidHeaderTermination
+            | ProxyError::InvalidChunkedBody
+            | ProxyError::UnexpectedData
+            | ProxyError::HeadersTooLarge(_) => IoErrorKind::InvalidData,
+        }
+    }
+}
+
+impl From<ProxyError> for IoError {
+    fn from(e: ProxyError) -> IoError {
+        IoError::new(e.kind(), e)
+    }
+}
+
+pub fn other_error(text: &str) -> IoError {
+    IoError::other(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auth_scheme() {
+        assert_eq!(AuthScheme::from_challenge("Basic realm=\"proxy\""), Some(AuthScheme::Basic));
+        assert_eq!(AuthScheme::from_challenge("digest realm=\"x\", nonce=\"y\""), Some(AuthScheme::Digest));
+        assert_eq!(AuthScheme::from_challenge("NTLM"), Some(AuthScheme::Ntlm));
+        assert_eq!(AuthScheme::from_challenge("Negotiate"), Some(AuthScheme::Other("Negotiate".into())));
+        assert_eq!(AuthScheme::from_challenge(""), None);
+    }
+
+    #[test]
+    fn test_from_io() {
+        let e: IoError = ProxyError::AuthRequired(vec![AuthScheme::Basic]).into();
+        assert_eq!(e.kind(), IoErrorKind::PermissionDenied);
+        assert_eq!(ProxyError::from_io(&e), Some(&ProxyError::AuthRequired(vec![AuthScheme::Basic])));
+        assert_eq!(ProxyError::from_io(&IoError::other("x")), None);
+        let e: IoError = ProxyError::Truncated("status line").into();
+        assert_eq!(e.kind(), IoErrorKind::UnexpectedEof);
+        assert_eq!(e.to_string(), "truncated status line");
+        assert_eq!(ProxyError::StatusNotSuccess(403, "Access denied".into()).to_string(), "Invalid status - 403: Access denied");
+        assert_eq!(ProxyError::StatusNotSuccess(502, "".into()).to_string(), "Invalid status - 502");
+        let e: IoError = ProxyError::Timeout { phase: TimeoutPhase::Response }.into();
+        assert_eq!(e.kind(), IoErrorKind::TimedOut);
+        assert_eq!(e.to_string(), "Proxy response timed out");
+    }
+}
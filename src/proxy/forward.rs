@@ -0,0 +1,155 @@
+use futures::{Future, Poll};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::net::IpAddr;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::write_all;
+
+/// Request line and headers of client request longer than this are rejected
+pub const MAX_REQUEST_HEAD: usize = 64 * 1024;
+
+/// Reads first request of client, adds X-Forwarded-For (with client IP, appended to
+/// value sent by client, if known) and X-Forwarded-Proto headers to it and writes it
+/// to remote end, together with any data read after headers. Resolves to both streams
+/// and number of bytes written. Connection, which does not start with HTTP/1.x request
+/// is refused, as remote end would not get client address.
+pub fn forward_request<L, R>(
+    local: L,
+    remote: R,
+    client: Option<IpAddr>,
+) -> Box<dyn Future<Item = (L, R, u64), Error = IoError> + Send>
+where
+    L: AsyncRead + Send + 'static,
+    R: AsyncWrite + Send + 'static,
+{
+    Box::new(ReadHead { reader: Some(local), buf: vec![] }.and_then(move |(local, buf, end)| {
+        let mut request = add_forwarded(&buf[..end], client)?;
+        request.extend_from_slice(&buf[end..]);
+        Ok((local, request))
+    })
+    .and_then(move |(local, request)| write_all(remote, request)
+        .map(move |(remote, request)| (local, remote, request.len() as u64))))
+}
+
+/// Reads from client, until there is complete request line and headers, resolves to reader,
+/// data read and end of headers in them
+struct ReadHead<R> {
+    reader: Option<R>,
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead> Future for ReadHead<R> {
+    type Item = (R, Vec<u8>, usize);
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<Self::Item, IoError> {
+        loop {
+            if let Some(end) = head_end(&self.buf) {
+                if end > MAX_REQUEST_HEAD {
+                    break;
+                }
+                let buf = ::std::mem::take(&mut self.buf);
+                return Ok((self.reader.take().unwrap(), buf, end).into());
+            }
+            if self.buf.len() >= MAX_REQUEST_HEAD {
+                break;
+            }
+            let mut chunk = [0u8; 4096];
+            let n = try_ready!(self.reader.as_mut().unwrap().poll_read(&mut chunk));
+            if n == 0 {
+                return Err(IoError::new(IoErrorKind::UnexpectedEof, "Client closed connection before end of request headers"));
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+        Err(IoError::new(IoErrorKind::InvalidData, format!("Request headers exceed {} bytes", MAX_REQUEST_HEAD)))
+    }
+}
+
+/// Position right after empty line ending headers
+fn head_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4)
+}
+
+fn is_header(line: &[u8], name: &str) -> bool {
+    line.len() > name.len() && line[name.len()] == b':' && line[..name.len()].eq_ignore_ascii_case(name.as_bytes())
+}
+
+/// Rewrites request line and headers (ending with empty line) - X-Forwarded-For headers
+/// sent by client are joined into one with client IP appended, X-Forwarded-Proto is replaced
+fn add_forwarded(head: &[u8], client: Option<IpAddr>) -> Result<Vec<u8>, IoError> {
+    let not_http = || IoError::new(IoErrorKind::InvalidData, "Client did not send HTTP/1.x request");
+    let mut lines = head[..head.len() - 4].split(|&b| b == b'\n');
+    let request_line = lines.next().ok_or_else(not_http)?;
+    let request_line = request_line.strip_suffix(b"\r").unwrap_or(request_line);
+    let parts: Vec<&[u8]> = request_line.split(|&b| b == b' ').collect();
+    if parts.len() != 3 || parts[0].is_empty() || !parts[2].starts_with(b"HTTP/1.") {
+        return Err(not_http());
+    }
+    let mut out = Vec::with_capacity(head.len() + 64);
+    out.extend_from_slice(request_line);
+    out.extend_from_slice(b"\r\n");
+    let mut forwarded_for = vec![];
+    for line in lines {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if is_header(line, "X-Forwarded-For") {
+            let value = String::from_utf8_lossy(&line[16..]).trim().to_owned();
+            if !value.is_empty() {
+                forwarded_for.push(value);
+            }
+        } else if !is_header(line, "X-Forwarded-Proto") {
+            out.extend_from_slice(line);
+            out.extend_from_slice(b"\r\n");
+        }
+    }
+    if let Some(ip) = client {
+        forwarded_for.push(ip.to_string());
+    }
+    if !forwarded_for.is_empty() {
+        out.extend_from_slice(format!("X-Forwarded-For: {}\r\n", forwarded_for.join(", ")).as_bytes());
+    }
+    out.extend_from_slice(b"X-Forwarded-Proto: http\r\n\r\n");
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_add_forwarded() {
+        let client = Some(IpAddr::from([192, 168, 1, 2]));
+        let head = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert_eq!(
+            add_forwarded(head, client).unwrap(),
+            b"GET / HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-For: 192.168.1.2\r\nX-Forwarded-Proto: http\r\n\r\n".to_vec()
+        );
+        let head = b"POST /a HTTP/1.0\r\nx-forwarded-for: 10.0.0.1\r\nX-Forwarded-Proto: https\r\nX-Forwarded-For: 10.0.0.2\r\n\r\n";
+        assert_eq!(
+            add_forwarded(head, client).unwrap(),
+            b"POST /a HTTP/1.0\r\nX-Forwarded-For: 10.0.0.1, 10.0.0.2, 192.168.1.2\r\nX-Forwarded-Proto: http\r\n\r\n".to_vec()
+        );
+        // client of Unix socket has no address
+        assert_eq!(
+            add_forwarded(b"GET / HTTP/1.1\r\n\r\n", None).unwrap(),
+            b"GET / HTTP/1.1\r\nX-Forwarded-Proto: http\r\n\r\n".to_vec()
+        );
+        assert!(add_forwarded(b"SSH-2.0-OpenSSH\r\n\r\n", client).is_err());
+        assert!(add_forwarded(b"PRI * HTTP/2.0\r\n\r\n", client).is_err());
+    }
+
+    #[test]
+    fn test_forward_request() {
+        let client = Some(IpAddr::from([127, 0, 0, 1]));
+        let data = b"GET / HTTP/1.1\r\nHost: x\r\n\r\nbody".to_vec();
+        let (_, remote, n) = forward_request(Cursor::new(data), Cursor::new(vec![]), client).wait().unwrap();
+        let expected = b"GET / HTTP/1.1\r\nHost: x\r\nX-Forwarded-For: 127.0.0.1\r\nX-Forwarded-Proto: http\r\n\r\nbody".to_vec();
+        assert_eq!(n, expected.len() as u64);
+        assert_eq!(remote.into_inner(), expected);
+
+        let f = |data: Vec<u8>| forward_request(Cursor::new(data), Cursor::new(vec![]), client).wait().err().unwrap().kind();
+        assert_eq!(f(b"GET / HTTP/1.1\r\nHost: x\r\n".to_vec()), IoErrorKind::UnexpectedEof);
+        let mut long = b"GET / HTTP/1.1\r\n".to_vec();
+        long.extend(vec![b'a'; MAX_REQUEST_HEAD]);
+        assert_eq!(f(long), IoErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,220 @@
+use futures::{Async, Future, Poll};
+use futures::task::AtomicTask;
+use std::cmp::min;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Token bucket limiting throughput of one direction of tunnel, shared by all its
+/// connections (clones use same bucket), bucket holds at most one second worth of bytes
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+    /// Wait for tokens, each clone has its own, so that every waiting task is woken
+    delay: Option<Delay>,
+}
+
+struct Bucket {
+    rate: u64,
+    tokens: u64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_nanos();
+        let added = elapsed * self.rate as u128 / NANOS_PER_SEC;
+        // time of partial token is kept for next refill
+        if added > 0 {
+            self.tokens = min(self.tokens as u128 + added, self.rate as u128) as u64;
+            self.last_refill = now;
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Rate is in bytes per second and must not be zero
+    pub fn new(rate: u64) -> Self {
+        assert!(rate > 0);
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate,
+                tokens: rate,
+                last_refill: Instant::now(),
+            })),
+            delay: None,
+        }
+    }
+
+    /// Number of bytes (at most wanted) which can be transferred now, when bucket is empty
+    /// current task is woken later, when tokens for at most 100ms of transfer accumulate.
+    /// Tasks sharing bucket may transfer together a bit more than acquired by one of them,
+    /// which is taken from later refills.
+    pub fn poll_acquire(&mut self, wanted: usize) -> Poll<usize, io::Error> {
+        loop {
+            if let Some(ref mut delay) = self.delay {
+                try_ready!(delay.poll().map_err(io::Error::other));
+            }
+            self.delay = None;
+            let now = Instant::now();
+            let mut bucket = self.bucket.lock().unwrap();
+            bucket.refill(now);
+            if bucket.tokens > 0 {
+                return Ok(Async::Ready(min(bucket.tokens, wanted as u64) as usize));
+            }
+            let needed = min(wanted as u64, bucket.rate / 10).max(1);
+            let wait = needed as u128 * NANOS_PER_SEC / bucket.rate as u128;
+            self.delay = Some(Delay::new(now + Duration::from_nanos(wait as u64)));
+        }
+    }
+
+    /// Takes tokens for bytes actually transferred
+    pub fn consume(&mut self, n: usize) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.tokens = bucket.tokens.saturating_sub(n as u64);
+    }
+}
+
+/// Clone shares bucket, but waits for tokens on its own
+impl Clone for RateLimiter {
+    fn clone(&self) -> Self {
+        RateLimiter { bucket: self.bucket.clone(), delay: None }
+    }
+}
+
+/// Limit of concurrent connections of one tunnel. Permits are acquired only
+/// by accept loop of the tunnel, so there is at most one task waiting for free slot.
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    inner: Arc<LimitInner>,
+}
+
+struct LimitInner {
+    max: usize,
+    active: AtomicUsize,
+    waiter: AtomicTask,
+}
+
+impl ConnectionLimit {
+    /// Limit without maximum just counts active connections
+    pub fn new(max: Option<usize>) -> Self {
+        ConnectionLimit {
+            inner: Arc::new(LimitInner {
+                max: max.unwrap_or(usize::MAX),
+                active: AtomicUsize::new(0),
+                waiter: AtomicTask::new(),
+            }),
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.inner.max
+    }
+
+    /// Number of connections holding permit
+    pub fn active(&self) -> usize {
+        self.inner.active.load(Ordering::SeqCst)
+    }
+
+    /// Permit, if there is free slot
+    pub fn try_acquire(&self) -> Option<Permit> {
+        let mut active = self.inner.active.load(Ordering::SeqCst);
+        loop {
+            if active >= self.inner.max {
+                return None;
+            }
+            match self.inner.active.compare_exchange(active, active + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Some(Permit { inner: self.inner.clone() }),
+                Err(n) => active = n,
+            }
+        }
+    }
+
+    /// Waits for free slot
+    pub fn acquire(&self) -> Acquire {
+        Acquire { limit: self.clone() }
+    }
+}
+
+/// Slot of one connection, released on drop
+pub struct Permit {
+    inner: Arc<LimitInner>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.inner.active.fetch_sub(1, Ordering::SeqCst);
+        self.inner.waiter.notify();
+    }
+}
+
+pub struct Acquire {
+    limit: ConnectionLimit,
+}
+
+impl Future for Acquire {
+    type Item = Permit;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Permit, io::Error> {
+        if let Some(p) = self.limit.try_acquire() {
+            return Ok(Async::Ready(p));
+        }
+        self.limit.inner.waiter.register();
+        // slot may have been released before registration
+        match self.limit.try_acquire() {
+            Some(p) => Ok(Async::Ready(p)),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_limit() {
+        let limit = ConnectionLimit::new(Some(2));
+        let p1 = limit.try_acquire().unwrap();
+        let _p2 = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+        assert_eq!(limit.active(), 2);
+        let waiting = ::std::thread::spawn({
+            let limit = limit.clone();
+            move || limit.acquire().wait().map(|_| ())
+        });
+        drop(p1);
+        waiting.join().unwrap().unwrap();
+        assert_eq!(limit.active(), 1);
+        assert!(ConnectionLimit::new(None).try_acquire().is_some());
+    }
+
+    #[test]
+    fn test_tokens() {
+        let mut l = RateLimiter::new(100);
+        assert_eq!(l.poll_acquire(1000).unwrap(), Async::Ready(100));
+        assert_eq!(l.poll_acquire(10).unwrap(), Async::Ready(10));
+        l.consume(100);
+        let mut bucket = l.bucket.lock().unwrap();
+        let start = bucket.last_refill;
+        bucket.refill(start + Duration::from_millis(55));
+        assert_eq!(bucket.tokens, 5);
+        bucket.refill(start + Duration::from_secs(10));
+        assert_eq!(bucket.tokens, 100);
+    }
+
+    #[test]
+    fn test_shared_tokens() {
+        let mut l = RateLimiter::new(100);
+        let mut other = l.clone();
+        assert_eq!(other.poll_acquire(60).unwrap(), Async::Ready(60));
+        other.consume(60);
+        assert_eq!(l.poll_acquire(1000).unwrap(), Async::Ready(40));
+        l.consume(40);
+        assert_eq!(other.bucket.lock().unwrap().tokens, 0);
+    }
+}
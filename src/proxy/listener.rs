@@ -0,0 +1,105 @@
+use futures::{Async, Future, Poll, Stream};
+use futures::stream::FuturesUnordered;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::{TcpListener, TcpStream};
+use config::{Proxy, Tunnel};
+use super::stream::ProxyTcpStream;
+use super::{connect_remote, proxy_protocol_header};
+
+type Pending = Box<dyn Future<Item = (TcpStream, ProxyTcpStream), Error = io::Error> + Send>;
+
+/// Accepts clients on local port of tunnel and connects remote end for each of them,
+/// yields pairs of client stream and remote stream, so that caller can process data itself.
+///
+/// Remote ends are connected concurrently, pairs are yielded in order connections complete.
+/// Failed connection is yielded as error (client connection is then closed) and stream
+/// can be polled further, unlike error from accept, which means listener is broken.
+pub struct TunnelListener {
+    listener: TcpListener,
+    tunnel: Tunnel,
+    proxy: Option<Proxy>,
+    pending: FuturesUnordered<Pending>,
+}
+
+impl TunnelListener {
+    /// Binds local port of tunnel, on its local_bind address or on given default address
+    pub fn bind(local_addr: IpAddr, tunnel: Tunnel, proxy: Option<Proxy>) -> io::Result<Self> {
+        let addr = SocketAddr::new(tunnel.local_bind.unwrap_or(local_addr), tunnel.local_port);
+        Ok(TunnelListener {
+            listener: TcpListener::bind(&addr)?,
+            tunnel,
+            proxy,
+            pending: FuturesUnordered::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    fn connect(&self, client: TcpStream, client_addr: SocketAddr) -> Pending {
+        let header = match self.tunnel.proxy_protocol {
+            Some(version) => match client.local_addr() {
+                Ok(local) => Some(proxy_protocol_header(version, Some((client_addr, local)))),
+                Err(e) => return Box::new(::futures::future::err(e)),
+            },
+            None => None,
+        };
+        let f = connect_remote(self.tunnel.clone(), self.proxy.as_ref(), header, None);
+        Box::new(f.map(move |remote| (client, remote)))
+    }
+}
+
+impl Stream for TunnelListener {
+    type Item = (TcpStream, ProxyTcpStream);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        while let Async::Ready((client, client_addr)) = self.listener.poll_accept()? {
+            debug!("[{}] Client connected from {}", self.tunnel, client_addr);
+            let f = self.connect(client, client_addr);
+            self.pending.push(f);
+        }
+        match self.pending.poll()? {
+            Async::Ready(Some(pair)) => Ok(Async::Ready(Some(pair))),
+            // nothing is connecting, listener will wake us with next client
+            Async::Ready(None) | Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_listener() {
+        let server = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let tunnel = Tunnel {
+            remote_host: "127.0.0.1".into(),
+            remote_port: server_addr.port(),
+            ..Default::default()
+        };
+        let listener = TunnelListener::bind("127.0.0.1".parse().unwrap(), tunnel, None).unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let client = ::std::thread::spawn(move || {
+            let mut c = ::std::net::TcpStream::connect(local_addr).unwrap();
+            c.write_all(b"ping").unwrap();
+        });
+        let mut rt = ::tokio::runtime::current_thread::Runtime::new().unwrap();
+        let (pair, _) = rt.block_on(listener.into_future()).map_err(|(e, _)| e).unwrap();
+        let (local, remote) = pair.unwrap();
+        assert_eq!(remote.peer_addr().unwrap(), server_addr);
+        assert!(!remote.is_proxied());
+        client.join().unwrap();
+        let (_, ping) = rt.block_on(::tokio_io::io::read_exact(local, [0u8; 4])).unwrap();
+        assert_eq!(&ping, b"ping");
+        let (mut accepted, _) = server.accept().unwrap();
+        drop(remote);
+        let mut buf = vec![];
+        assert_eq!(accepted.read_to_end(&mut buf).unwrap(), 0);
+    }
+}
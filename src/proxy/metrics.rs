@@ -0,0 +1,160 @@
+use futures::{future, Future, Stream};
+use futures::future::Loop;
+use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::Timeout;
+use tokio_io::io::{read, shutdown, write_all};
+use super::stats::{totals, tunnel_throughput, Throughput, Totals};
+
+/// Longest request accepted by metrics endpoint
+const MAX_REQUEST: usize = 8 * 1024;
+/// Slow scraper is disconnected after this time
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Current statistics of this process in Prometheus text exposition format
+pub fn text() -> String {
+    render(&totals(), &tunnel_throughput())
+}
+
+fn render(t: &Totals, throughput: &[(String, Throughput)]) -> String {
+    let mut s = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, values: &[(&str, String)]| {
+        let _ = writeln!(s, "# HELP ptunnel_{} {}", name, help);
+        let _ = writeln!(s, "# TYPE ptunnel_{} {}", name, kind);
+        for &(labels, ref value) in values {
+            let _ = writeln!(s, "ptunnel_{}{} {}", name, labels, value);
+        }
+    };
+    metric("active_connections", "gauge", "Connections to remote end currently open", &[("", t.active.to_string())]);
+    metric("connections_total", "counter", "Connections to remote end established", &[("", t.connections.to_string())]);
+    metric("connect_failures_total", "counter", "Failed connections to remote end", &[("", t.failures.to_string())]);
+    metric("received_bytes_total", "counter", "Bytes received from remote end", &[("", t.bytes_in.to_string())]);
+    metric("sent_bytes_total", "counter", "Bytes sent to remote end", &[("", t.bytes_out.to_string())]);
+    let classes = ["{class=\"1xx\"}", "{class=\"2xx\"}", "{class=\"3xx\"}", "{class=\"4xx\"}", "{class=\"5xx\"}"];
+    let handshakes: Vec<_> = classes.iter().zip(&t.handshakes).map(|(c, n)| (*c, n.to_string())).collect();
+    metric("proxy_responses_total", "counter", "Proxy responses to CONNECT by status class", &handshakes);
+    metric("fallbacks_total", "counter", "Direct connections made because proxy could not be reached", &[("", t.fallbacks.to_string())]);
+    metric("failovers_total", "counter", "Connections tried via next proxy because previous one failed", &[("", t.failovers.to_string())]);
+    metric("retries_total", "counter", "Attempts to connect via proxy repeated after transient failure", &[("", t.retries.to_string())]);
+    metric("dns_cache_hits_total", "counter", "Lookups answered from DNS cache", &[("", t.dns_hits.to_string())]);
+    metric("dns_cache_misses_total", "counter", "Lookups not found in DNS cache", &[("", t.dns_misses.to_string())]);
+    let labels = |name: &str, direction| format!("{{tunnel=\"{}\",direction=\"{}\"}}", name, direction);
+    let rates: Vec<_> = throughput
+        .iter()
+        .flat_map(|(name, t)| vec![(labels(name, "received"), t.bytes_in.to_string()), (labels(name, "sent"), t.bytes_out.to_string())])
+        .collect();
+    let rates: Vec<_> = rates.iter().map(|(l, v)| (&l[..], v.clone())).collect();
+    metric("tunnel_throughput_bytes", "gauge", "Bytes per second of tunnel over sampling window", &rates);
+    let active: Vec<_> = t.tunnel_active.iter().map(|(name, n)| (format!("{{tunnel=\"{}\"}}", name), n.to_string())).collect();
+    let active: Vec<_> = active.iter().map(|(l, v)| (&l[..], v.clone())).collect();
+    metric("tunnel_active_connections", "gauge", "Client connections of tunnel currently open", &active);
+    s
+}
+
+/// Serves metrics over HTTP on given address, at path /metrics
+pub fn serve(addr: SocketAddr) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+    let listener = match TcpListener::bind(&addr) {
+        Ok(l) => l,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    info!("Serving metrics on http://{}/metrics", addr);
+    Box::new(listener.incoming().for_each(|s| {
+        let f = Timeout::new(respond(s), REQUEST_TIMEOUT)
+            .map_err(|e| debug!("Metrics request failed: {}", e));
+        tokio::spawn(f);
+        Ok(())
+    }))
+}
+
+/// Reads request head and answers it, only GET of /metrics is supported
+fn respond(s: TcpStream) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+    let head = future::loop_fn((s, vec![]), |(s, mut head)| {
+        read(s, vec![0u8; 1024]).and_then(move |(s, buf, n)| {
+            head.extend_from_slice(&buf[..n]);
+            if n == 0 || head.windows(4).any(|w| w == b"\r\n\r\n") {
+                Ok(Loop::Break((s, head)))
+            } else if head.len() > MAX_REQUEST {
+                Err(io::Error::new(io::ErrorKind::InvalidData, "Request too long"))
+            } else {
+                Ok(Loop::Continue((s, head)))
+            }
+        })
+    });
+    Box::new(head.and_then(|(s, head)| {
+        let response = match head.split(|&b| b == b' ').nth(1) {
+            Some(path) if head.starts_with(b"GET ") && path == b"/metrics" => {
+                let body = text();
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            }
+            _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned(),
+        };
+        write_all(s, response).and_then(|(s, _)| shutdown(s)).map(|_| ())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_render() {
+        let t = Totals {
+            active: 2,
+            connections: 5,
+            failures: 1,
+            dns_hits: 0,
+            dns_misses: 0,
+            handshakes: [0, 4, 0, 1, 0],
+            fallbacks: 3,
+            failovers: 0,
+            retries: 4,
+            bytes_in: 100,
+            bytes_out: 50,
+            tunnel_active: vec![("9993->imap.example.com:993".to_owned(), 3)],
+        };
+        let throughput = vec![("9993->imap.example.com:993".to_owned(), Throughput { bytes_in: 2048, bytes_out: 10 })];
+        let text = render(&t, &throughput);
+        assert!(text.contains("# TYPE ptunnel_active_connections gauge\nptunnel_active_connections 2\n"));
+        assert!(text.contains("ptunnel_proxy_responses_total{class=\"2xx\"} 4\n"));
+        assert!(text.contains("ptunnel_proxy_responses_total{class=\"4xx\"} 1\n"));
+        assert!(text.contains("ptunnel_fallbacks_total 3\n"));
+        assert!(text.contains("ptunnel_retries_total 4\n"));
+        assert!(text.contains("ptunnel_received_bytes_total 100\n"));
+        assert!(text.contains("ptunnel_tunnel_throughput_bytes{tunnel=\"9993->imap.example.com:993\",direction=\"received\"} 2048\n"));
+        assert!(text.contains("ptunnel_tunnel_active_connections{tunnel=\"9993->imap.example.com:993\"} 3\n"));
+    }
+
+    #[test]
+    fn test_serve() {
+        let port = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let addr: SocketAddr = ([127, 0, 0, 1], port).into();
+        // server thread is left running, as it is just blocked in accept
+        ::std::thread::spawn(move || {
+            let mut rt = ::tokio::runtime::current_thread::Runtime::new().unwrap();
+            rt.block_on(serve(addr)).unwrap();
+        });
+        let get = |path: &str| {
+            let mut c = (0..100)
+                .filter_map(|_| ::std::net::TcpStream::connect(addr).map_err(|_| ::std::thread::sleep(Duration::from_millis(10))).ok())
+                .next()
+                .unwrap();
+            write!(c, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            c.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("ptunnel_connections_total "));
+        assert!(get("/").starts_with("HTTP/1.1 404 "));
+    }
+}
@@ -1,69 +1,669 @@
-use futures::{future, Future, Stream};
+use futures::{future, Async, Future, Poll, Stream};
+use futures::future::Either;
+use futures::future::Shared;
+use futures::sync::oneshot;
 use tokio_io::io;
 use tokio;
 use tokio::net::TcpListener;
 use std::net::SocketAddr;
-use config::{Proxy, Tunnel};
-use self::stream::{FixedTcpStream, ProxyTcpStream};
+use std::time::{Duration, Instant};
+use tokio::timer::Interval;
+use config::{ConnectHook, Proxy, ProxyProtocol, Tunnel};
+use self::stream::{ClientAddr, FixedTcpStream, PollClosed};
+#[cfg(unix)]
+use self::stream::FixedUnixStream;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+#[cfg(unix)]
+use std::path::Path;
+use tokio_io::{AsyncRead, AsyncWrite};
+use self::copy::CloseReason;
+use self::limit::{ConnectionLimit, Permit, RateLimiter};
+use self::pool::Pool;
+use self::stats::Direction;
+use self::supervisor::Supervisor;
 
+pub use self::error::{AuthScheme, ProxyError, TimeoutPhase};
+pub use self::listener::TunnelListener;
+pub use self::metrics::{serve as serve_metrics, text as metrics_text};
+pub use self::stats::{drain, log_totals, totals, tunnel_throughput, Stats, Throughput, ThroughputSampler, Totals};
+pub use self::stream::{OwnedProxyStream, ProxyTcpStream};
+pub use self::supervisor::supervise;
+
+mod chunked;
+mod copy;
+mod digest;
+mod dns;
+mod error;
+mod forward;
+mod limit;
+mod listener;
+mod metrics;
+mod pool;
+mod race;
+mod socks;
+mod stats;
 mod stream;
+mod supervisor;
+mod trace;
+
+/// Connects remote end, or takes prewarmed connection from pool, and sends PROXY protocol header, if given
+fn connect_remote(
+    tunnel: Tunnel,
+    proxy: Option<&Proxy>,
+    header: Option<Vec<u8>>,
+    pool: Option<&Pool>,
+) -> Box<dyn Future<Item = ProxyTcpStream, Error = ::std::io::Error> + Send> {
+    let f: Box<dyn Future<Item = ProxyTcpStream, Error = ::std::io::Error> + Send> = match pool {
+        Some(pool) => {
+            let (pool, proxy) = (pool.clone(), proxy.cloned());
+            Box::new(future::lazy(move || {
+                let prewarmed = pool.take(&tunnel);
+                // replaces taken connection (and those found closed)
+                pool.fill(&tunnel, proxy.as_ref());
+                match prewarmed {
+                    Some(s) => {
+                        debug!("[{}] Using prewarmed connection", tunnel);
+                        Box::new(future::ok(s))
+                    }
+                    None => ProxyTcpStream::connect(tunnel, proxy.as_ref()),
+                }
+            }))
+        }
+        None => ProxyTcpStream::connect(tunnel, proxy),
+    };
+    match header {
+        Some(h) => Box::new(f.and_then(move |s| io::write_all(s, h).map(|(s, _)| s))),
+        None => f,
+    }
+}
+
+/// Signature at start of PROXY protocol v2 header
+const PROXY_V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\x00\r\nQUIT\n";
+
+/// PROXY protocol header, describing client connection (client and local address) to local port.
+/// When addresses are not known or are of different families, header says so.
+fn proxy_protocol_header(version: ProxyProtocol, addrs: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    match version {
+        ProxyProtocol::V1 => proxy_protocol_v1(addrs).into_bytes(),
+        ProxyProtocol::V2 => proxy_protocol_v2(addrs),
+    }
+}
+
+fn proxy_protocol_v1(addrs: Option<(SocketAddr, SocketAddr)>) -> String {
+    match addrs {
+        Some((SocketAddr::V4(c), SocketAddr::V4(l))) => {
+            format!("PROXY TCP4 {} {} {} {}\r\n", c.ip(), l.ip(), c.port(), l.port())
+        }
+        Some((SocketAddr::V6(c), SocketAddr::V6(l))) => {
+            format!("PROXY TCP6 {} {} {} {}\r\n", c.ip(), l.ip(), c.port(), l.port())
+        }
+        _ => "PROXY UNKNOWN\r\n".to_owned(),
+    }
+}
+
+/// Binary header - signature, version and command (PROXY), address family and protocol,
+/// length of address block and the block with addresses and ports in network byte order
+fn proxy_protocol_v2(addrs: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let (family, block) = match addrs {
+        Some((SocketAddr::V4(c), SocketAddr::V4(l))) => {
+            let mut block = vec![];
+            block.extend_from_slice(&c.ip().octets());
+            block.extend_from_slice(&l.ip().octets());
+            block.extend_from_slice(&c.port().to_be_bytes());
+            block.extend_from_slice(&l.port().to_be_bytes());
+            // TCP over IPv4
+            (0x11, block)
+        }
+        Some((SocketAddr::V6(c), SocketAddr::V6(l))) => {
+            let mut block = vec![];
+            block.extend_from_slice(&c.ip().octets());
+            block.extend_from_slice(&l.ip().octets());
+            block.extend_from_slice(&c.port().to_be_bytes());
+            block.extend_from_slice(&l.port().to_be_bytes());
+            // TCP over IPv6
+            (0x21, block)
+        }
+        // unspecified, receiver ignores address block
+        _ => (0x00, vec![]),
+    };
+    let mut header = PROXY_V2_SIGNATURE.to_vec();
+    header.push(0x21);
+    header.push(family);
+    header.extend_from_slice(&(block.len() as u16).to_be_bytes());
+    header.extend(block);
+    header
+}
+
+/// Rate limiters of tunnel for upload and download, shared by all its connections
+type RateLimiters = (RateLimiter, RateLimiter);
+
+type RelayFuture = Box<dyn Future<Item = (u64, u64, CloseReason), Error = ::std::io::Error> + Send>;
+
+/// Copies data in both directions until both ends are closed, resolves to
+/// bytes uploaded and downloaded and to the side, which closed connection first.
+///
+/// With reconnect enabled, new connection to remote end is made, when remote end
+/// closes connection while client is still connected. This happens only if
+/// all data received from client were already sent to remote end and some data were
+/// transferred over closed connection (so that remote end closing connection
+/// immediately does not cause reconnection loop).
+fn relay<L>(
+    local: L,
+    remote: ProxyTcpStream,
+    tunnel: Tunnel,
+    proxy: Option<Proxy>,
+    header: Option<Vec<u8>>,
+    limiters: Option<RateLimiters>,
+) -> RelayFuture
+where
+    L: AsyncRead + AsyncWrite + Clone + Send + 'static,
+{
+    let buffer_size = tunnel.buffer_size.unwrap_or(copy::DEFAULT_BUFFER_SIZE);
+    let (up, down) = match limiters {
+        Some((ref up, ref down)) => (Some(up.clone()), Some(down.clone())),
+        None => (None, None),
+    };
+    let sampler = stats::tunnel_sampler(&tunnel);
+    let forward = copy::copy(local.clone(), remote.clone(), buffer_size, up, Some((sampler.clone(), Direction::Out)));
+    let backward = copy::copy(remote.clone(), local.clone(), buffer_size, down, Some((sampler, Direction::In)));
+
+    if !tunnel.reconnect {
+        let forward = forward.and_then(|(n, _, writer)| io::shutdown(writer).map(move |_| n));
+        let backward = backward.and_then(|(n, _, writer)| io::shutdown(writer).map(move |_| n));
+        let f = forward.select2(backward).then(|res| -> RelayFuture {
+            match res {
+                Ok(Either::A((up, backward))) => Box::new(backward.map(move |down| (up, down, CloseReason::LocalEof))),
+                Ok(Either::B((down, forward))) => Box::new(forward.map(move |up| (up, down, CloseReason::RemoteEof))),
+                Err(Either::A((e, _))) | Err(Either::B((e, _))) => Box::new(future::err(e)),
+            }
+        });
+        return Box::new(f);
+    }
+
+    let f = backward.select2(forward).then(move |res| -> RelayFuture {
+        match res {
+            // remote end closed connection
+            Ok(Either::A(((down, _, writer), forward))) => {
+                let up = forward.copied();
+                if forward.is_flushed() && up + down > 0 {
+                    drop(forward);
+                    info!("[{}] Remote end closed connection, reconnecting", tunnel);
+                    let t = tunnel.clone();
+                    Box::new(connect_remote(tunnel, proxy.as_ref(), header.clone(), None)
+                        .and_then(move |mut new_remote| {
+                            new_remote.share_counters(&remote);
+                            relay(local, new_remote, t, proxy, header, limiters)
+                        })
+                        .map(move |(u, d, reason)| (up + u, down + d, reason)))
+                } else {
+                    Box::new(io::shutdown(writer)
+                        .and_then(|_| forward)
+                        .and_then(move |(up, _, writer)| {
+                            io::shutdown(writer).map(move |_| (up, down, CloseReason::RemoteEof))
+                        }))
+                }
+            }
+            // client closed connection
+            Ok(Either::B(((up, _, writer), backward))) => Box::new(io::shutdown(writer)
+                .and_then(|_| backward)
+                .and_then(move |(down, _, writer)| {
+                    io::shutdown(writer).map(move |_| (up, down, CloseReason::LocalEof))
+                })),
+            Err(Either::A((e, _))) | Err(Either::B((e, _))) => Box::new(future::err(e)),
+        }
+    });
+    Box::new(f)
+}
+
+/// Checks that all tunnels can be connected, resolves to number of failed ones
+pub fn check_tunnels(
+    tunnels: Vec<Tunnel>,
+    proxy: Option<Proxy>,
+) -> Box<dyn Future<Item = usize, Error = ()> + Send> {
+    let checks = tunnels.into_iter().map(move |t| {
+        let name = t.to_string();
+        ProxyTcpStream::check(t, proxy.as_ref()).then(move |res| {
+            match res {
+                Ok(()) => info!("[{}] Check OK", name),
+                Err(ref e) => error!("[{}] Check failed: {}", name, e),
+            }
+            Ok::<_, ()>(res.is_err() as usize)
+        })
+    });
+    Box::new(future::join_all(checks.collect::<Vec<_>>()).map(|r| r.iter().sum()))
+}
 
+/// Resolves when no data were transferred over stream for given time
+fn idle_watchdog(
+    stream: ProxyTcpStream,
+    timeout: Duration,
+) -> Box<dyn Future<Item = (), Error = ::std::io::Error> + Send> {
+    let check = (timeout / 2).clamp(Duration::from_millis(10), Duration::from_secs(1));
+    let f = Interval::new(Instant::now() + check, check)
+        .map_err(::std::io::Error::other)
+        .take_while(move |_| Ok(stream.stats().idle < timeout))
+        .for_each(|_| Ok(()));
+    Box::new(f)
+}
 
+/// Signals tunnels to stop accepting new connections, fires on send or drop of sender
+pub type Shutdown = Shared<oneshot::Receiver<()>>;
+
+/// Permit for accepted client, None if client should be rejected because of connection limit
+fn admit(limit: &ConnectionLimit, tunnel: &Tunnel) -> Box<dyn Future<Item = Option<Permit>, Error = ::std::io::Error> + Send> {
+    if let Some(p) = limit.try_acquire() {
+        return Box::new(future::ok(Some(p)));
+    }
+    if tunnel.queue_connections {
+        warn!("[{}] Connection limit {} reached, waiting for free slot", tunnel, limit.max());
+        Box::new(limit.acquire().map(Some))
+    } else {
+        warn!("[{}] Connection limit {} reached, closing new connection", tunnel, limit.max());
+        Box::new(future::ok(None))
+    }
+}
 
 pub fn run_tunnel(
     local_addr: ::std::net::IpAddr,
     tunnel: Tunnel,
     proxy: Option<Proxy>,
-    user: Option<String>
-) -> Box<Future<Item = (), Error = ::std::io::Error>+Send> {
-    // Bind the server's socket
-    let addr = SocketAddr::new(local_addr, tunnel.local_port);
-    let tcp = match TcpListener::bind(&addr) {
-        Ok(l) => l,
-        Err(e) => return Box::new(future::err(e)),
+    shutdown: Shutdown,
+) -> Box<dyn Future<Item = (), Error = ::std::io::Error>+Send> {
+    serve(local_addr, tunnel, proxy, shutdown, None)
+}
+
+/// Runs tunnel, connections to remote end are made through supervisor, if given
+fn serve(
+    local_addr: ::std::net::IpAddr,
+    tunnel: Tunnel,
+    proxy: Option<Proxy>,
+    shutdown: Shutdown,
+    supervisor: Option<Supervisor>,
+) -> Box<dyn Future<Item = (), Error = ::std::io::Error>+Send> {
+    // failed connections are counted in statistics, configured hook is still called
+    let mut tunnel = tunnel;
+    let hook = tunnel.on_connect.take();
+    tunnel.on_connect = Some(ConnectHook::new(move |event| {
+        stats::count_connect(event);
+        if let Some(ref h) = hook {
+            h.call(event)
+        }
+    }));
+    let tunnel_name = tunnel.to_string();
+    let (tunnel_prewarm, proxy_prewarm) = (tunnel.clone(), proxy.clone());
+    let limit = ConnectionLimit::new(tunnel.max_connections);
+    stats::register_tunnel_limit(&tunnel, &limit);
+    let limiters = tunnel.rate_limit.map(|rate| (RateLimiter::new(rate), RateLimiter::new(rate)));
+    let pool = if tunnel.prewarm > 0 {
+        Some(Pool::new(tunnel.prewarm))
+    } else {
+        None
     };
+    let (pool2, pool3) = (pool.clone(), pool.clone());
+    let server: Box<dyn Future<Item = (), Error = ::std::io::Error> + Send> = match tunnel.local_path.clone() {
+        #[cfg(unix)]
+        Some(path) => {
+            let listener = match bind_unix(&path) {
+                Ok(l) => l,
+                Err(e) => return Box::new(future::err(e)),
+            };
+            Box::new(listener.incoming().for_each(move |s| {
+                debug!("[{}] Client connected ({} active connections)", tunnel, limit.active());
+                // client address is not known, so PROXY header can only say so
+                let header = tunnel.proxy_protocol.map(|v| proxy_protocol_header(v, None));
+                let (tunnel, proxy, pool, supervisor) = (tunnel.clone(), proxy.clone(), pool.clone(), supervisor.clone());
+                let limiters = limiters.clone();
+                admit(&limit, &tunnel).map(move |permit| if let Some(permit) = permit {
+                    tokio::spawn(handle_client(FixedUnixStream::from(s), tunnel, proxy, header, permit, limiters, pool, supervisor));
+                })
+            }))
+        }
+        #[cfg(not(unix))]
+        Some(_) => return Box::new(future::err(::std::io::Error::other("Unix sockets are not supported"))),
+        None => {
+            // Bind the server's socket
+            let addr = SocketAddr::new(tunnel.local_bind.unwrap_or(local_addr), tunnel.local_port);
+            let tcp = match TcpListener::bind(&addr) {
+                Ok(l) => l,
+                Err(e) => return Box::new(future::err(e)),
+            };
 
-    // Iterate incoming connections
-    let server = tcp.incoming().for_each(move |tcp| {
-        let client_addr = tcp.peer_addr().unwrap();
-        debug!("Client connected from {}", client_addr);
-        let tunnel2 = tunnel.clone();
-        let remote = ProxyTcpStream::connect(
-            tunnel.clone(),
-            proxy.as_ref(),
-            user.clone()
-        ).map_err(move |e| {
-            error!(
-                "cannot connect remote end {} because of error {}",
-                tunnel2.remote(),
-                e
-            );
-            // TODO: Close connection?
-        })
-            .and_then(move |remote_socket| {
-                debug!("Created upstream {:?}", remote_socket);
-                let reader = FixedTcpStream::from(tcp);
-                let writer = reader.clone();
-
-                let remote_reader = remote_socket;
-                let remote_writer = remote_reader.clone();
-
-                let copy_forward = io::copy(reader, remote_writer)
-                    .and_then(|(n, _, writer)| io::shutdown(writer).map(move |_| n));
-
-                let copy_backward = io::copy(remote_reader, writer)
-                    .and_then(|(n, _, writer)| io::shutdown(writer).map(move |_| n));
-
-                copy_forward
-                    .join(copy_backward)
-                    .map(|(up, down)| {
-                        debug!("Uploaded {} bytes and downloaded {} bytes", up, down)
-                    })
-                    .map_err(|e| warn!("Tunnel connection error {}", e))
-            });
-        tokio::spawn(remote);
-        Ok(())
+            // Iterate incoming connections
+            Box::new(tcp.incoming().for_each(move |tcp| {
+                // client can reset connection before it is handled
+                let client_addr = match tcp.peer_addr() {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        error!("[{}] Cannot get address of client, dropping connection: {}", tunnel, e);
+                        return Either::A(future::ok(()));
+                    }
+                };
+                debug!("[{}] Client connected from {} ({} active connections)", tunnel, client_addr, limit.active());
+                let header = match tunnel.proxy_protocol {
+                    Some(version) => match tcp.local_addr() {
+                        Ok(local) => Some(proxy_protocol_header(version, Some((client_addr, local)))),
+                        Err(e) => {
+                            error!("[{}] Cannot get local address for PROXY header: {}", tunnel, e);
+                            return Either::A(future::ok(()));
+                        }
+                    },
+                    None => None,
+                };
+                let (tunnel, proxy, pool, supervisor) = (tunnel.clone(), proxy.clone(), pool.clone(), supervisor.clone());
+                let limiters = limiters.clone();
+                Either::B(admit(&limit, &tunnel).map(move |permit| if let Some(permit) = permit {
+                    tokio::spawn(handle_client(FixedTcpStream::from(tcp), tunnel, proxy, header, permit, limiters, pool, supervisor));
+                }))
+            }))
+        }
+    };
+
+    // pool is filled on runtime, as connections are spawned
+    let server = future::lazy(move || {
+        if let Some(ref pool) = pool2 {
+            pool.fill(&tunnel_prewarm, proxy_prewarm.as_ref());
+        }
+        server
     });
 
+    // already accepted connections are left running, only listener is closed
+    let name = tunnel_name;
+    let server = server
+        .select(shutdown.then(move |_| {
+            info!("[{}] Stopped accepting connections", name);
+            // prewarmed connections would delay shutdown
+            if let Some(pool) = pool3 {
+                pool.clear();
+            }
+            Ok(())
+        }))
+        .map(|_| ())
+        .map_err(|(e, _)| e);
+
     Box::new(server)
 }
+
+/// Binds Unix socket, socket file left from previous run is removed.
+/// On Linux path starting with @ is name of abstract socket, which has no file
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> ::std::io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(name) = abstract_name(path) {
+            return UnixListener::bind(name);
+        }
+    }
+    if let Ok(meta) = ::std::fs::symlink_metadata(path) {
+        if meta.file_type().is_socket() {
+            ::std::fs::remove_file(path)?;
+        }
+    }
+    UnixListener::bind(path)
+}
+
+/// Abstract socket address is given to bind as path with leading null byte instead of @
+#[cfg(target_os = "linux")]
+fn abstract_name(path: &Path) -> Option<::std::path::PathBuf> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    let bytes = path.as_os_str().as_bytes();
+    if bytes.first() != Some(&b'@') {
+        return None;
+    }
+    let mut name = vec![0];
+    name.extend_from_slice(&bytes[1..]);
+    Some(OsStr::from_bytes(&name).into())
+}
+
+/// Logs end of tunneled connection with its reason, when dropped. Connection dropped
+/// without known outcome was still open, when runtime was stopped on shutdown.
+struct CloseLog {
+    tunnel: Tunnel,
+    remote: ProxyTcpStream,
+    /// Reason and bytes uploaded and downloaded, if known from relay
+    outcome: Option<(CloseReason, Option<(u64, u64)>)>,
+}
+
+impl CloseLog {
+    fn close(&mut self, reason: CloseReason, transferred: Option<(u64, u64)>) {
+        self.outcome = Some((reason, transferred));
+    }
+}
+
+impl Drop for CloseLog {
+    fn drop(&mut self) {
+        let stats = self.remote.stats();
+        let (reason, transferred) = self.outcome.unwrap_or((CloseReason::Shutdown, None));
+        // otherwise bytes are counted on remote connection, including proxy handshake
+        let (up, down) = transferred.unwrap_or((stats.bytes_out, stats.bytes_in));
+        let id = self.remote.id();
+        match reason {
+            CloseReason::LocalEof | CloseReason::RemoteEof => debug!(
+                "[{}] Connection #{} closed ({}), uploaded {} bytes and downloaded {} bytes in {:?}",
+                self.tunnel, id, reason, up, down, stats.duration
+            ),
+            _ => info!(
+                "[{}] Connection #{} closed ({}), uploaded {} bytes and downloaded {} bytes in {:?}",
+                self.tunnel, id, reason, up, down, stats.duration
+            ),
+        }
+    }
+}
+
+/// Connection to remote end made for client, which is cancelled when client disconnects
+/// before it completes - connect future is dropped, which closes its socket and abandons
+/// proxy handshake. Resolves to client and remote end, or to None when client is gone.
+struct UnlessClientGone<L> {
+    local: Option<L>,
+    connect: Box<dyn Future<Item = ProxyTcpStream, Error = ::std::io::Error> + Send>,
+}
+
+impl<L: PollClosed> Future for UnlessClientGone<L> {
+    type Item = Option<(L, ProxyTcpStream)>;
+    type Error = ::std::io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::Ready(remote) = self.connect.poll()? {
+            return Ok(Async::Ready(self.local.take().map(|l| (l, remote))));
+        }
+        if self.local.as_mut().is_none_or(|l| l.poll_closed()) {
+            self.local = None;
+            return Ok(Async::Ready(None));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// Connects remote end for accepted client and forwards data between them
+#[allow(clippy::too_many_arguments)]
+fn handle_client<L>(
+    local: L,
+    tunnel: Tunnel,
+    proxy: Option<Proxy>,
+    header: Option<Vec<u8>>,
+    permit: Permit,
+    limiters: Option<RateLimiters>,
+    pool: Option<Pool>,
+    supervisor: Option<Supervisor>,
+) -> Box<dyn Future<Item = (), Error = ()> + Send>
+where
+    L: AsyncRead + AsyncWrite + PollClosed + ClientAddr + Clone + Send + 'static,
+{
+    let tunnel2 = tunnel.clone();
+    let tunnel3 = tunnel.clone();
+    let proxy2 = proxy.clone();
+    let header2 = header.clone();
+    let remote = match supervisor {
+        Some(s) => s.connect(tunnel.clone(), proxy.clone(), header, pool),
+        None => connect_remote(tunnel.clone(), proxy.as_ref(), header, pool.as_ref()),
+    };
+    let remote = UnlessClientGone { local: Some(local), connect: remote };
+    let remote = remote.map_err(move |e| {
+        error!(
+            "[{}] cannot connect remote end {} because of error {}",
+            tunnel2,
+            tunnel2.remote(),
+            e
+        );
+        if let Some(&ProxyError::AuthRequired(_)) = ProxyError::from_io(&e) {
+            error!("Proxy requires authentication, check --user and --password arguments");
+        }
+        // TODO: Close connection?
+    })
+        .and_then(move |connected| {
+            let (local, remote_socket) = match connected {
+                Some(c) => c,
+                None => {
+                    debug!("[{}] Client disconnected before remote end was connected, connecting cancelled", tunnel3);
+                    return Either::A(future::ok(()));
+                }
+            };
+            let path = if remote_socket.is_proxied() { "via proxy" } else { "directly" };
+            let id = remote_socket.id();
+            match (remote_socket.peer_addr(), remote_socket.local_addr()) {
+                (Ok(peer), Ok(local)) => {
+                    debug!("[{}] Created upstream #{} {} connected to {} from {}", tunnel3, id, path, peer, local)
+                }
+                (Err(e), _) | (_, Err(e)) => {
+                    debug!("[{}] Created upstream #{} {}, but its address is unknown: {}", tunnel3, id, path, e)
+                }
+            }
+            trace!("[{}] Proxy response headers of #{} {:?}", tunnel3, id, remote_socket.response_headers());
+            let mut close_log = CloseLog { tunnel: tunnel3.clone(), remote: remote_socket.clone(), outcome: None };
+            let tunnel4 = tunnel3.clone();
+            let idle_stats = remote_socket.clone();
+            // first request is forwarded with added headers, rest of connection as it is
+            let request: Box<dyn Future<Item = (L, u64), Error = ::std::io::Error> + Send> = if tunnel3.forward_headers {
+                let client = local.client_ip();
+                Box::new(forward::forward_request(local, remote_socket.clone(), client).map(|(local, _, n)| (local, n)))
+            } else {
+                Box::new(future::ok((local, 0)))
+            };
+            let transfer = request.and_then(move |(local, forwarded)| {
+                relay(local, remote_socket, tunnel3, proxy2, header2, limiters)
+                    .map(move |(up, down, reason)| Some((up + forwarded, down, reason)))
+            });
+            // dropping transfer closes both sockets
+            let transfer: Box<dyn Future<Item = Option<(u64, u64, CloseReason)>, Error = ::std::io::Error> + Send> =
+                match tunnel4.idle_timeout {
+                    Some(timeout) => Box::new(transfer
+                        .select(idle_watchdog(idle_stats, timeout).map(|_| None))
+                        .map(|(outcome, _)| outcome)
+                        .map_err(|(e, _)| e)),
+                    None => Box::new(transfer),
+                };
+            Either::B(transfer.then(move |res| {
+                match res {
+                    Ok(Some((up, down, reason))) => close_log.close(reason, Some((up, down))),
+                    Ok(None) => close_log.close(CloseReason::IdleTimeout, None),
+                    Err(e) => {
+                        warn!("[{}] Tunnel connection #{} error {}", tunnel4, id, e);
+                        close_log.close(CloseReason::Error, None)
+                    }
+                }
+                Ok::<_, ()>(())
+            }))
+        });
+    // slot is released, when client connection is finished
+    Box::new(remote.then(move |res| {
+        drop(permit);
+        res
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_protocol_header() {
+        let addrs = |c: &str, l: &str| Some((c.parse().unwrap(), l.parse().unwrap()));
+        assert_eq!(
+            proxy_protocol_header(ProxyProtocol::V1, addrs("192.168.1.2:50000", "127.0.0.1:9993")),
+            b"PROXY TCP4 192.168.1.2 127.0.0.1 50000 9993\r\n".to_vec()
+        );
+        assert_eq!(
+            proxy_protocol_header(ProxyProtocol::V1, addrs("[::1]:50000", "[::1]:9993")),
+            b"PROXY TCP6 ::1 ::1 50000 9993\r\n".to_vec()
+        );
+        assert_eq!(
+            proxy_protocol_header(ProxyProtocol::V1, addrs("[::1]:50000", "127.0.0.1:9993")),
+            b"PROXY UNKNOWN\r\n".to_vec()
+        );
+        assert_eq!(proxy_protocol_header(ProxyProtocol::V1, None), b"PROXY UNKNOWN\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_proxy_protocol_v2_header() {
+        let addrs = |c: &str, l: &str| Some((c.parse().unwrap(), l.parse().unwrap()));
+        let mut expected = PROXY_V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0, 12, 192, 168, 1, 2, 127, 0, 0, 1, 0xc3, 0x50, 0x27, 0x09]);
+        assert_eq!(proxy_protocol_header(ProxyProtocol::V2, addrs("192.168.1.2:50000", "127.0.0.1:9993")), expected);
+
+        let h = proxy_protocol_header(ProxyProtocol::V2, addrs("[2001:db8::1]:50000", "[::1]:9993"));
+        assert_eq!(h.len(), 16 + 36);
+        assert_eq!(&h[12..16], &[0x21, 0x21, 0, 36]);
+        assert_eq!(&h[16..20], &[0x20, 0x01, 0x0d, 0xb8]);
+        assert_eq!(&h[47..52], &[1, 0xc3, 0x50, 0x27, 0x09]);
+
+        let mut expected = PROXY_V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x00, 0, 0]);
+        assert_eq!(proxy_protocol_header(ProxyProtocol::V2, addrs("[::1]:50000", "127.0.0.1:9993")), expected);
+        assert_eq!(proxy_protocol_header(ProxyProtocol::V2, None), expected);
+    }
+
+    #[test]
+    fn test_relay_close_reason() {
+        use std::io::{Read, Write};
+        use std::net::{Shutdown, TcpListener as StdListener, TcpStream as StdStream};
+        let remote_listener = StdListener::bind("127.0.0.1:0").unwrap();
+        let local_listener = StdListener::bind("127.0.0.1:0").unwrap();
+        let tunnel = Tunnel {
+            remote_host: "127.0.0.1".into(),
+            remote_port: remote_listener.local_addr().unwrap().port(),
+            ..Default::default()
+        };
+        let mut rt = ::tokio::runtime::current_thread::Runtime::new().unwrap();
+        // both ends are handled in threads, each end is closed when its function returns
+        let mut run = |server: fn(StdStream), client: fn(StdStream)| {
+            let remote = rt.block_on(ProxyTcpStream::connect(tunnel.clone(), None)).unwrap();
+            let server_stream = remote_listener.accept().unwrap().0;
+            let server = ::std::thread::spawn(move || server(server_stream));
+            let client_stream = StdStream::connect(local_listener.local_addr().unwrap()).unwrap();
+            let client = ::std::thread::spawn(move || client(client_stream));
+            let local = tokio::net::TcpStream::from_std(local_listener.accept().unwrap().0, &tokio::reactor::Handle::default()).unwrap();
+            let res = rt.block_on(relay(FixedTcpStream::from(local), remote, tunnel.clone(), None, None, None)).unwrap();
+            server.join().unwrap();
+            client.join().unwrap();
+            res
+        };
+        let res = run(
+            |mut s| s.write_all(b"hi").unwrap(),
+            |mut c| c.read_to_end(&mut vec![]).map(|_| ()).unwrap(),
+        );
+        assert_eq!(res, (0, 2, CloseReason::RemoteEof));
+        let res = run(
+            |mut s| s.read_to_end(&mut vec![]).map(|_| ()).unwrap(),
+            |mut c| {
+                c.write_all(b"abc").unwrap();
+                c.shutdown(Shutdown::Write).unwrap();
+                c.read_to_end(&mut vec![]).unwrap();
+            },
+        );
+        assert_eq!(res, (3, 0, CloseReason::LocalEof));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_bind_abstract() {
+        use std::os::linux::net::SocketAddrExt;
+        let name = format!("ptunnel-test-{}", ::std::process::id());
+        let path = format!("@{}", name);
+        let _listener = bind_unix(Path::new(&path)).unwrap();
+        assert!(!Path::new(&path).exists());
+        let addr = ::std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        assert!(::std::os::unix::net::UnixStream::connect_addr(&addr).is_ok());
+    }
+}
@@ -0,0 +1,96 @@
+use futures::Future;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio;
+use config::{Proxy, Tunnel};
+use super::stream::ProxyTcpStream;
+
+/// Connections to remote end of one tunnel established in advance, so that
+/// client does not have to wait for connection and proxy handshake
+#[derive(Clone)]
+pub struct Pool {
+    size: usize,
+    connections: Arc<Mutex<VecDeque<ProxyTcpStream>>>,
+    // connections being established
+    pending: Arc<AtomicUsize>,
+}
+
+impl Pool {
+    pub fn new(size: usize) -> Self {
+        Pool {
+            size,
+            connections: Arc::new(Mutex::new(VecDeque::new())),
+            pending: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Starts new connections to fill pool to its size, must be called on runtime
+    pub fn fill(&self, tunnel: &Tunnel, proxy: Option<&Proxy>) {
+        let ready = self.connections.lock().unwrap().len();
+        let missing = self.size.saturating_sub(ready + self.pending.load(Ordering::SeqCst));
+        for _ in 0..missing {
+            self.pending.fetch_add(1, Ordering::SeqCst);
+            let (connections, pending) = (self.connections.clone(), self.pending.clone());
+            let name = tunnel.to_string();
+            tokio::spawn(ProxyTcpStream::connect(tunnel.clone(), proxy).then(move |res| {
+                pending.fetch_sub(1, Ordering::SeqCst);
+                match res {
+                    Ok(s) => connections.lock().unwrap().push_back(s),
+                    Err(e) => warn!("[{}] Cannot prewarm connection: {}", name, e),
+                }
+                Ok(())
+            }));
+        }
+    }
+
+    /// Takes connection which is still open, connections already closed by remote end
+    /// (or by proxy as idle) are discarded. Must be called from task.
+    pub fn take(&self, tunnel: &Tunnel) -> Option<ProxyTcpStream> {
+        let mut connections = self.connections.lock().unwrap();
+        while let Some(mut s) = connections.pop_front() {
+            if s.poll_closed() {
+                debug!("[{}] Discarding closed prewarmed connection", tunnel);
+            } else {
+                return Some(s);
+            }
+        }
+        None
+    }
+
+    /// Closes all prewarmed connections
+    pub fn clear(&self) {
+        self.connections.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use std::time::{Duration, Instant};
+    use tokio::timer::Delay;
+
+    #[test]
+    fn test_pool() {
+        let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let tunnel = Tunnel{local_port: 2222, remote_host: "127.0.0.1".into(), remote_port: port, ..Default::default()};
+        let pool = Pool::new(2);
+        let mut rt = ::tokio::runtime::current_thread::Runtime::new().unwrap();
+        rt.block_on(future::lazy(|| {
+            pool.fill(&tunnel, None);
+            Ok::<_, ()>(())
+        })).unwrap();
+        // first connection is closed by remote end
+        let server = ::std::thread::spawn(move || {
+            drop(listener.accept().unwrap());
+            listener.accept().unwrap()
+        });
+        // reactor must run to establish connections and notice closed one
+        rt.block_on(Delay::new(Instant::now() + Duration::from_millis(200))).unwrap();
+        let _second = server.join().unwrap();
+        assert!(rt.block_on(future::lazy(|| Ok::<_, ()>(pool.take(&tunnel)))).unwrap().is_some());
+        assert!(rt.block_on(future::lazy(|| Ok::<_, ()>(pool.take(&tunnel)))).unwrap().is_none());
+    }
+}
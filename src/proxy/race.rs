@@ -0,0 +1,136 @@
+use futures::{Async, Future, Poll};
+use std::collections::VecDeque;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::timer::Delay;
+use tokio_io::IoFuture;
+
+/// Delay before next address is tried, while previous attempt is still running (RFC 8305)
+pub const DEFAULT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+type Connect = Box<dyn Fn(SocketAddr) -> IoFuture<TcpStream> + Send>;
+
+/// Connects to addresses in given order - next attempt starts when previous one fails
+/// or when delay elapses, whichever comes first. First connected socket wins and other
+/// attempts are dropped, which cancels them. Fails with error of last failed attempt.
+pub struct Race {
+    pending: VecDeque<SocketAddr>,
+    attempts: Vec<(SocketAddr, IoFuture<TcpStream>)>,
+    connect: Connect,
+    delay: Duration,
+    next: Delay,
+    last_error: Option<IoError>,
+}
+
+pub fn race<F>(addrs: Vec<SocketAddr>, delay: Duration, connect: F) -> Race
+where
+    F: Fn(SocketAddr) -> IoFuture<TcpStream> + Send + 'static,
+{
+    Race {
+        pending: addrs.into(),
+        attempts: vec![],
+        connect: Box::new(connect),
+        delay,
+        next: Delay::new(Instant::now()),
+        last_error: None,
+    }
+}
+
+impl Future for Race {
+    type Item = TcpStream;
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<TcpStream, IoError> {
+        loop {
+            let mut progress = false;
+            let start = !self.pending.is_empty()
+                && (self.attempts.is_empty() || self.next.poll().map_err(IoError::other)?.is_ready());
+            if start {
+                let addr = self.pending.pop_front().unwrap();
+                trace!("Connecting to {}", addr);
+                self.attempts.push((addr, (self.connect)(addr)));
+                self.next.reset(Instant::now() + self.delay);
+                progress = true;
+            }
+            let mut i = 0;
+            while i < self.attempts.len() {
+                match self.attempts[i].1.poll() {
+                    Ok(Async::Ready(s)) => {
+                        if self.attempts.len() > 1 {
+                            debug!("Connected to {}, cancelling {} other attempts", self.attempts[i].0, self.attempts.len() - 1);
+                        }
+                        return Ok(Async::Ready(s));
+                    }
+                    Ok(Async::NotReady) => i += 1,
+                    Err(e) => {
+                        debug!("Connection to {} failed: {}", self.attempts[i].0, e);
+                        self.attempts.remove(i);
+                        self.last_error = Some(e);
+                        // next address does not wait for delay of failed attempt
+                        self.next.reset(Instant::now());
+                        progress = true;
+                    }
+                }
+            }
+            if self.attempts.is_empty() && self.pending.is_empty() {
+                return Err(self.last_error.take().unwrap_or_else(|| {
+                    IoError::new(IoErrorKind::AddrNotAvailable, "Host has no address")
+                }));
+            }
+            if !progress {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_race() {
+        let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let good = listener.local_addr().unwrap();
+        // attempts to these never finish, so only delay can start next one
+        let hanging: SocketAddr = "10.255.255.1:1".parse().unwrap();
+        let failing: SocketAddr = "10.255.255.2:1".parse().unwrap();
+        let started = Arc::new(Mutex::new(vec![]));
+        let connect = {
+            let started = started.clone();
+            move |addr: SocketAddr| -> IoFuture<TcpStream> {
+                started.lock().unwrap().push(addr);
+                if addr == hanging {
+                    Box::new(future::empty())
+                } else if addr == failing {
+                    Box::new(future::err(IoError::new(IoErrorKind::ConnectionRefused, "refused")))
+                } else {
+                    Box::new(future::lazy(move || TcpStream::connect(&addr)))
+                }
+            }
+        };
+        let mut rt = ::tokio::runtime::current_thread::Runtime::new().unwrap();
+        let delay = Duration::from_millis(500);
+        let f = race(vec![hanging, failing, good, hanging], delay, connect);
+        let start = Instant::now();
+        let s = rt.block_on(f).unwrap();
+        assert_eq!(s.peer_addr().unwrap(), good);
+        // failed attempt starts next one right away, while hanging one is still pending,
+        // so only delay after first attempt is waited for, last address is not needed
+        assert_eq!(*started.lock().unwrap(), vec![hanging, failing, good]);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= delay && elapsed < delay * 3 / 2, "{:?}", elapsed);
+
+        let refused = |_| -> IoFuture<TcpStream> {
+            Box::new(future::err(IoError::new(IoErrorKind::ConnectionRefused, "refused")))
+        };
+        let e = rt.block_on(race(vec![failing, failing], Duration::from_secs(10), refused)).unwrap_err();
+        assert_eq!(e.kind(), IoErrorKind::ConnectionRefused);
+        let e = rt.block_on(race(vec![], Duration::from_secs(10), refused)).unwrap_err();
+        assert_eq!(e.kind(), IoErrorKind::AddrNotAvailable);
+    }
+}
@@ -0,0 +1,185 @@
+use futures::{future, Future};
+use tokio_io::io::{read_exact, write_all};
+use tokio_io::IoFuture;
+use std::io::Result as IoResult;
+use std::net::IpAddr;
+use config::{Proxy, Tunnel};
+use super::error::other_error;
+use super::stream::ProxyTcpStream;
+
+const VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0;
+const METHOD_USER_PASS: u8 = 2;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+/// Version of username/password subnegotiation (RFC 1929)
+const AUTH_VERSION: u8 = 1;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Performs SOCKS5 handshake (RFC 1928) on already connected stream,
+/// resolves to the stream ready for tunneled data
+pub fn handshake(stream: ProxyTcpStream, tun: &Tunnel, proxy: &Proxy) -> IoFuture<ProxyTcpStream> {
+    let request = match connect_request(tun) {
+        Ok(r) => r,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let credentials = proxy
+        .username
+        .clone()
+        .map(|u| (u, proxy.password.clone().unwrap_or_default()));
+
+    let f = write_all(stream, greeting(credentials.is_some()))
+        .and_then(|(stream, _)| read_exact(stream, [0u8; 2]))
+        .and_then(move |(stream, reply)| -> IoFuture<ProxyTcpStream> {
+            if reply[0] != VERSION {
+                return Box::new(future::err(other_error("Invalid SOCKS version")));
+            }
+            match (reply[1], credentials) {
+                (METHOD_NO_AUTH, _) => Box::new(future::ok(stream)),
+                (METHOD_USER_PASS, Some((user, password))) => authenticate(stream, &user, &password),
+                (METHOD_NONE_ACCEPTABLE, _) => Box::new(future::err(other_error(
+                    "SOCKS proxy did not accept any offered authentication method",
+                ))),
+                (_, _) => Box::new(future::err(other_error(
+                    "SOCKS proxy selected unsupported authentication method",
+                ))),
+            }
+        })
+        .and_then(|stream| write_all(stream, request))
+        .and_then(|(stream, _)| read_exact(stream, [0u8; 4]))
+        .and_then(|(stream, reply)| -> IoFuture<ProxyTcpStream> {
+            if reply[0] != VERSION {
+                return Box::new(future::err(other_error("Invalid SOCKS version")));
+            }
+            if reply[1] != 0 {
+                return Box::new(future::err(other_error(&format!(
+                    "SOCKS connect failed - {}",
+                    reply_error(reply[1])
+                ))));
+            }
+            // skip bound address, we do not use it
+            let f: IoFuture<ProxyTcpStream> = match reply[3] {
+                ATYP_IPV4 => Box::new(read_exact(stream, vec![0u8; 4 + 2]).map(|(s, _)| s)),
+                ATYP_IPV6 => Box::new(read_exact(stream, vec![0u8; 16 + 2]).map(|(s, _)| s)),
+                ATYP_DOMAIN => Box::new(
+                    read_exact(stream, [0u8; 1])
+                        .and_then(|(s, len)| read_exact(s, vec![0u8; len[0] as usize + 2]))
+                        .map(|(s, _)| s),
+                ),
+                _ => Box::new(future::err(other_error("Invalid SOCKS address type"))),
+            };
+            f
+        });
+
+    Box::new(f)
+}
+
+fn authenticate(stream: ProxyTcpStream, user: &str, password: &str) -> IoFuture<ProxyTcpStream> {
+    let request = match auth_request(user, password) {
+        Ok(r) => r,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let f = write_all(stream, request)
+        .and_then(|(stream, _)| read_exact(stream, [0u8; 2]))
+        .and_then(|(stream, reply)| check_auth_reply(reply).map(|_| stream));
+    Box::new(f)
+}
+
+fn check_auth_reply(reply: [u8; 2]) -> IoResult<()> {
+    match reply {
+        [AUTH_VERSION, 0] => Ok(()),
+        [AUTH_VERSION, _] => Err(other_error("SOCKS authentication failed")),
+        _ => Err(other_error("Invalid SOCKS authentication version")),
+    }
+}
+
+fn greeting(with_auth: bool) -> Vec<u8> {
+    if with_auth {
+        vec![VERSION, 2, METHOD_NO_AUTH, METHOD_USER_PASS]
+    } else {
+        vec![VERSION, 1, METHOD_NO_AUTH]
+    }
+}
+
+fn auth_request(user: &str, password: &str) -> IoResult<Vec<u8>> {
+    if user.len() > 255 || password.len() > 255 {
+        return Err(other_error("SOCKS username or password too long"));
+    }
+    let mut req = vec![AUTH_VERSION, user.len() as u8];
+    req.extend_from_slice(user.as_bytes());
+    req.push(password.len() as u8);
+    req.extend_from_slice(password.as_bytes());
+    Ok(req)
+}
+
+/// Builds CONNECT request, host names are sent as they are to be resolved by proxy
+fn connect_request(tun: &Tunnel) -> IoResult<Vec<u8>> {
+    let mut req = vec![VERSION, CMD_CONNECT, 0];
+    match tun.remote_host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            req.push(ATYP_IPV4);
+            req.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            req.push(ATYP_IPV6);
+            req.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let host = tun.remote_host.as_bytes();
+            if host.is_empty() || host.len() > 255 {
+                return Err(other_error("Invalid host name for SOCKS"));
+            }
+            req.push(ATYP_DOMAIN);
+            req.push(host.len() as u8);
+            req.extend_from_slice(host);
+        }
+    }
+    req.push((tun.remote_port >> 8) as u8);
+    req.push(tun.remote_port as u8);
+    Ok(req)
+}
+
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        1 => "general SOCKS server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tunnel(host: &str) -> Tunnel {
+        Tunnel{local_port: 2222, remote_host: host.into(), remote_port: 22, ..Default::default()}
+    }
+
+    #[test]
+    fn test_connect_request() {
+        assert_eq!(connect_request(&tunnel("a.com")).unwrap(),
+            vec![5, 1, 0, 3, 5, b'a', b'.', b'c', b'o', b'm', 0, 22]);
+        assert_eq!(connect_request(&tunnel("10.0.0.1")).unwrap(),
+            vec![5, 1, 0, 1, 10, 0, 0, 1, 0, 22]);
+        assert_eq!(connect_request(&tunnel("::1")).unwrap().len(), 4 + 16 + 2);
+        assert!(connect_request(&tunnel("")).is_err());
+    }
+
+    #[test]
+    fn test_auth_request() {
+        assert_eq!(auth_request("u", "pw").unwrap(), vec![1, 1, b'u', 2, b'p', b'w']);
+        assert!(check_auth_reply([1, 0]).is_ok());
+        assert!(check_auth_reply([1, 1]).is_err());
+        assert!(check_auth_reply([5, 0]).is_err());
+        assert_eq!(greeting(false), vec![5, 1, 0]);
+        assert_eq!(greeting(true), vec![5, 2, 0, 2]);
+    }
+}
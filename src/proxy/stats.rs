@@ -0,0 +1,434 @@
+use futures::{Future, Stream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use config::{ConnectEvent, Tunnel};
+use super::limit::ConnectionLimit;
+use tokio::timer::{Interval, Timeout};
+
+/// Counters shared by all clones of one stream, relaxed ordering is enough
+/// as counters are independent and only read for reporting
+#[derive(Debug)]
+pub struct StreamCounters {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    started: Instant,
+    // milliseconds since start
+    last_active: AtomicU64,
+}
+
+impl StreamCounters {
+    pub fn new() -> Self {
+        TOTALS.active.fetch_add(1, Ordering::Relaxed);
+        StreamCounters {
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            started: Instant::now(),
+            last_active: AtomicU64::new(0),
+        }
+    }
+
+    pub fn add_in(&self, n: usize) {
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+        TOTALS.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+        self.touch();
+    }
+
+    pub fn add_out(&self, n: usize) {
+        self.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+        TOTALS.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+        self.touch();
+    }
+
+    fn touch(&self) {
+        let elapsed = self.started.elapsed();
+        self.last_active.store(elapsed.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Stats {
+        let duration = self.started.elapsed();
+        let last_active = Duration::from_millis(self.last_active.load(Ordering::Relaxed));
+        Stats {
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            duration,
+            idle: duration.checked_sub(last_active).unwrap_or_default(),
+        }
+    }
+}
+
+impl Drop for StreamCounters {
+    fn drop(&mut self) {
+        TOTALS.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Statistics of one stream - bytes read from it, bytes written to it,
+/// time since it has been connected and time since last read or write
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub duration: Duration,
+    pub idle: Duration,
+}
+
+/// Throughput is sampled over this interval, when tunnel does not set one
+pub const DEFAULT_THROUGHPUT_INTERVAL: Duration = Duration::from_secs(1);
+/// Throughput is averaged over this window, when tunnel does not set one
+pub const DEFAULT_THROUGHPUT_WINDOW: Duration = Duration::from_secs(10);
+
+/// Bytes per second received from remote end and sent to it
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Throughput {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// Direction of transfer, from point of view of remote end (as in Stats)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+struct Slot {
+    // number of sampling interval since start, which slot currently counts
+    epoch: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+/// Rolling throughput of tunnel - bytes are added to slot of current sampling interval
+/// in ring buffer, which covers the window plus current (partial) interval. Slots are
+/// reused, so recording does not allocate and needs only few relaxed atomic operations.
+/// Bytes recorded concurrently just when slot is reused can be lost, which is fine for estimate.
+pub struct ThroughputSampler {
+    interval_ms: u64,
+    started: Instant,
+    slots: Box<[Slot]>,
+}
+
+impl ThroughputSampler {
+    pub fn new(interval: Duration, window: Duration) -> Self {
+        let interval_ms = (interval.as_millis() as u64).max(1);
+        let intervals = (window.as_millis() as u64).div_ceil(interval_ms).max(1);
+        let slots = (0..=intervals)
+            .map(|_| Slot {
+                epoch: AtomicU64::new(0),
+                bytes_in: AtomicU64::new(0),
+                bytes_out: AtomicU64::new(0),
+            })
+            .collect();
+        ThroughputSampler {
+            interval_ms,
+            started: Instant::now(),
+            slots,
+        }
+    }
+
+    pub fn record(&self, direction: Direction, n: usize) {
+        self.record_at(direction, n, self.started.elapsed());
+    }
+
+    fn record_at(&self, direction: Direction, n: usize, elapsed: Duration) {
+        let epoch = elapsed.as_millis() as u64 / self.interval_ms;
+        let slot = &self.slots[(epoch % self.slots.len() as u64) as usize];
+        let previous = slot.epoch.load(Ordering::Relaxed);
+        if previous != epoch && slot.epoch.compare_exchange(previous, epoch, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            slot.bytes_in.store(0, Ordering::Relaxed);
+            slot.bytes_out.store(0, Ordering::Relaxed);
+        }
+        let counter = match direction {
+            Direction::In => &slot.bytes_in,
+            Direction::Out => &slot.bytes_out,
+        };
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Average throughput over the window, or over time since start if it is shorter
+    pub fn current_throughput(&self) -> Throughput {
+        self.throughput_at(self.started.elapsed())
+    }
+
+    fn throughput_at(&self, elapsed: Duration) -> Throughput {
+        let elapsed_ms = elapsed.as_millis() as u64;
+        let epoch = elapsed_ms / self.interval_ms;
+        let first = epoch.saturating_sub(self.slots.len() as u64 - 1);
+        let covered_ms = elapsed_ms - first * self.interval_ms;
+        if covered_ms == 0 {
+            return Throughput::default();
+        }
+        let (mut bytes_in, mut bytes_out) = (0, 0);
+        for slot in self.slots.iter() {
+            let e = slot.epoch.load(Ordering::Relaxed);
+            if e >= first && e <= epoch {
+                bytes_in += slot.bytes_in.load(Ordering::Relaxed);
+                bytes_out += slot.bytes_out.load(Ordering::Relaxed);
+            }
+        }
+        Throughput {
+            bytes_in: bytes_in * 1000 / covered_ms,
+            bytes_out: bytes_out * 1000 / covered_ms,
+        }
+    }
+}
+
+impl ::std::fmt::Debug for ThroughputSampler {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "ThroughputSampler({:?})", self.current_throughput())
+    }
+}
+
+lazy_static! {
+    // samplers of tunnels by tunnel name, they are kept for whole run as tunnels are
+    static ref SAMPLERS: Mutex<Vec<(String, Arc<ThroughputSampler>)>> = Mutex::new(vec![]);
+}
+
+/// Sampler of tunnel, created on first use with interval and window of tunnel
+pub fn tunnel_sampler(tunnel: &Tunnel) -> Arc<ThroughputSampler> {
+    let name = tunnel.to_string();
+    let mut samplers = SAMPLERS.lock().unwrap();
+    if let Some((_, s)) = samplers.iter().find(|(n, _)| *n == name) {
+        return s.clone();
+    }
+    let sampler = Arc::new(ThroughputSampler::new(
+        tunnel.throughput_interval.unwrap_or(DEFAULT_THROUGHPUT_INTERVAL),
+        tunnel.throughput_window.unwrap_or(DEFAULT_THROUGHPUT_WINDOW),
+    ));
+    samplers.push((name, sampler.clone()));
+    sampler
+}
+
+/// Current throughput of tunnels, which already transferred some data
+pub fn tunnel_throughput() -> Vec<(String, Throughput)> {
+    let samplers = SAMPLERS.lock().unwrap();
+    samplers.iter().map(|(n, s)| (n.clone(), s.current_throughput())).collect()
+}
+
+lazy_static! {
+    // connection limits of tunnels by tunnel name, for their counts of active connections
+    static ref LIMITS: Mutex<Vec<(String, ConnectionLimit)>> = Mutex::new(vec![]);
+}
+
+/// Registers connection limit of running tunnel, replacing limit of previous tunnel with same name
+pub fn register_tunnel_limit(tunnel: &Tunnel, limit: &ConnectionLimit) {
+    let name = tunnel.to_string();
+    let mut limits = LIMITS.lock().unwrap();
+    limits.retain(|(n, _)| *n != name);
+    limits.push((name, limit.clone()));
+}
+
+struct TotalCounters {
+    active: AtomicUsize,
+    connections: AtomicUsize,
+    failures: AtomicUsize,
+    dns_hits: AtomicUsize,
+    dns_misses: AtomicUsize,
+    /// Proxy responses to CONNECT by status class 1xx-5xx
+    handshakes: [AtomicUsize; 5],
+    fallbacks: AtomicUsize,
+    failovers: AtomicUsize,
+    retries: AtomicUsize,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+lazy_static! {
+    static ref TOTALS: TotalCounters = TotalCounters {
+        active: AtomicUsize::new(0),
+        connections: AtomicUsize::new(0),
+        failures: AtomicUsize::new(0),
+        dns_hits: AtomicUsize::new(0),
+        dns_misses: AtomicUsize::new(0),
+        handshakes: Default::default(),
+        fallbacks: AtomicUsize::new(0),
+        failovers: AtomicUsize::new(0),
+        retries: AtomicUsize::new(0),
+        bytes_in: AtomicU64::new(0),
+        bytes_out: AtomicU64::new(0),
+    };
+}
+
+/// Aggregate statistics over all streams in this process
+#[derive(Debug, Clone, PartialEq)]
+pub struct Totals {
+    pub active: usize,
+    /// Established connections to remote end, each counted once (not its retried attempts)
+    pub connections: usize,
+    /// Failed connections to remote end
+    pub failures: usize,
+    /// Lookups of DNS cache (only when caching is enabled)
+    pub dns_hits: usize,
+    pub dns_misses: usize,
+    /// Proxy responses to CONNECT by status class, index 0 is 1xx
+    pub handshakes: [usize; 5],
+    /// Direct connections made because proxy could not be reached
+    pub fallbacks: usize,
+    /// Connections tried via next proxy because previous one failed
+    pub failovers: usize,
+    /// Attempts to connect via proxy repeated after transient failure
+    pub retries: usize,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Client connections of each tunnel currently open (counted against its connection limit)
+    pub tunnel_active: Vec<(String, usize)>,
+}
+
+pub fn totals() -> Totals {
+    Totals {
+        active: TOTALS.active.load(Ordering::Relaxed),
+        connections: TOTALS.connections.load(Ordering::Relaxed),
+        failures: TOTALS.failures.load(Ordering::Relaxed),
+        dns_hits: TOTALS.dns_hits.load(Ordering::Relaxed),
+        dns_misses: TOTALS.dns_misses.load(Ordering::Relaxed),
+        handshakes: [
+            TOTALS.handshakes[0].load(Ordering::Relaxed),
+            TOTALS.handshakes[1].load(Ordering::Relaxed),
+            TOTALS.handshakes[2].load(Ordering::Relaxed),
+            TOTALS.handshakes[3].load(Ordering::Relaxed),
+            TOTALS.handshakes[4].load(Ordering::Relaxed),
+        ],
+        fallbacks: TOTALS.fallbacks.load(Ordering::Relaxed),
+        failovers: TOTALS.failovers.load(Ordering::Relaxed),
+        retries: TOTALS.retries.load(Ordering::Relaxed),
+        bytes_in: TOTALS.bytes_in.load(Ordering::Relaxed),
+        bytes_out: TOTALS.bytes_out.load(Ordering::Relaxed),
+        tunnel_active: LIMITS.lock().unwrap().iter().map(|(n, l)| (n.clone(), l.active())).collect(),
+    }
+}
+
+/// Connect hook counting failed connections
+pub fn count_connect(event: &ConnectEvent) {
+    if let ConnectEvent::Failed(_) = *event {
+        TOTALS.failures.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn count_dns(hit: bool) {
+    let counter = if hit { &TOTALS.dns_hits } else { &TOTALS.dns_misses };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts proxy response to CONNECT, invalid status is ignored
+pub fn count_handshake(status: u16) {
+    if let Some(counter) = (status / 100).checked_sub(1).and_then(|i| TOTALS.handshakes.get(i as usize)) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn count_connection() {
+    TOTALS.connections.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn count_retry() {
+    TOTALS.retries.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn count_fallback() {
+    TOTALS.fallbacks.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn count_failover() {
+    TOTALS.failovers.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Periodically logs aggregate statistics
+pub fn log_totals(interval: Duration) -> Box<dyn Future<Item = (), Error = ::std::io::Error> + Send> {
+    let f = Interval::new(Instant::now() + interval, interval)
+        .map_err(::std::io::Error::other)
+        .for_each(|_| {
+            let t = totals();
+            info!(
+                "Active connections {}, total connections {}, failed connections {}, DNS cache hits {} misses {}, bytes received {}, bytes sent {}",
+                t.active, t.connections, t.failures, t.dns_hits, t.dns_misses, t.bytes_in, t.bytes_out
+            );
+            Ok(())
+        });
+    Box::new(f)
+}
+
+/// Resolves when there are no active streams, or when grace period elapses
+pub fn drain(grace: Duration) -> Box<dyn Future<Item = (), Error = ::std::io::Error> + Send> {
+    let mut last_active = 0;
+    let f = Interval::new(Instant::now(), Duration::from_millis(250))
+        .map_err(::std::io::Error::other)
+        .take_while(move |_| {
+            let active = totals().active;
+            if active > 0 && active != last_active {
+                info!("Waiting for {} active connections to finish", active);
+            }
+            last_active = active;
+            Ok(active > 0)
+        })
+        .for_each(|_| Ok(()));
+    let f = Timeout::new(f, grace).or_else(|e| {
+        if e.is_elapsed() {
+            warn!("Shutdown grace period elapsed, closing {} active connections", totals().active);
+            Ok(())
+        } else {
+            Err(e.into_inner().unwrap_or_else(|| ::std::io::Error::other("timer failed")))
+        }
+    });
+    Box::new(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters() {
+        let before = totals();
+        let c = StreamCounters::new();
+        count_connection();
+        c.add_in(10);
+        c.add_out(5);
+        c.add_in(1);
+        let s = c.snapshot();
+        assert_eq!((s.bytes_in, s.bytes_out), (11, 5));
+        let after = totals();
+        // other tests may run in parallel, so just check lower bounds
+        assert!(after.bytes_in >= before.bytes_in + 11);
+        assert!(after.connections > before.connections);
+    }
+
+    #[test]
+    fn test_count_handshake() {
+        let before = totals();
+        count_handshake(200);
+        count_handshake(407);
+        count_handshake(99);
+        count_handshake(600);
+        let after = totals();
+        assert!(after.handshakes[1] > before.handshakes[1]);
+        assert!(after.handshakes[3] > before.handshakes[3]);
+    }
+
+    #[test]
+    fn test_throughput_sampler() {
+        let s = ThroughputSampler::new(Duration::from_secs(1), Duration::from_secs(4));
+        let at = Duration::from_millis;
+        assert_eq!(s.throughput_at(at(0)), Throughput::default());
+        s.record_at(Direction::In, 1000, at(100));
+        s.record_at(Direction::Out, 500, at(1500));
+        // over 2 seconds since start
+        assert_eq!(s.throughput_at(at(2000)), Throughput { bytes_in: 500, bytes_out: 250 });
+        s.record_at(Direction::In, 4000, at(4500));
+        // window of 4 seconds plus current partial second, first second is still in
+        assert_eq!(s.throughput_at(at(4500)), Throughput { bytes_in: 1111, bytes_out: 111 });
+        // first slot is reused for 6th second, old bytes are dropped
+        s.record_at(Direction::In, 100, at(5200));
+        assert_eq!(s.throughput_at(at(5200)), Throughput { bytes_in: 976, bytes_out: 119 });
+        assert_eq!(s.throughput_at(at(20000)), Throughput::default());
+    }
+
+    #[test]
+    fn test_idle() {
+        let c = StreamCounters::new();
+        ::std::thread::sleep(Duration::from_millis(20));
+        assert!(c.snapshot().idle >= Duration::from_millis(20));
+        c.add_out(1);
+        assert!(c.snapshot().idle < Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,233 @@
+use futures::{future, Future};
+use futures::future::Loop;
+use futures::sync::oneshot;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio;
+use tokio::timer::Delay;
+use config::{Backoff, Proxy, Tunnel};
+use super::pool::Pool;
+use super::stream::ProxyTcpStream;
+use super::{connect_remote, serve, Shutdown};
+
+/// Connection for one client is retried at most this many times, so that client does not wait forever
+const CLIENT_RETRIES: u32 = 3;
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = IoError> + Send>;
+
+struct State {
+    delay: Duration,
+    last_failure: Option<Instant>,
+    /// Reason of permanent failure, which stopped tunnel
+    stopped: Option<String>,
+    stop: Option<oneshot::Sender<()>>,
+}
+
+/// Backoff shared by tunnel and all its connections, so that many clients
+/// failing at once do not hammer proxy
+#[derive(Clone)]
+pub struct Supervisor {
+    backoff: Backoff,
+    state: Arc<Mutex<State>>,
+}
+
+impl Supervisor {
+    fn new(backoff: Backoff, stop: oneshot::Sender<()>) -> Self {
+        Supervisor {
+            backoff,
+            state: Arc::new(Mutex::new(State {
+                delay: backoff.initial,
+                last_failure: None,
+                stopped: None,
+                stop: Some(stop),
+            })),
+        }
+    }
+
+    /// Records failure and returns delay before next attempt
+    fn failed(&self, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        if let Some(last) = state.last_failure {
+            if now.duration_since(last) >= self.backoff.healthy {
+                state.delay = self.backoff.initial;
+            }
+        }
+        let delay = state.delay;
+        state.delay = (delay * 2).min(self.backoff.max);
+        state.last_failure = Some(now);
+        with_jitter(delay)
+    }
+
+    /// Stops listener of tunnel, reason is kept only for permanent failure
+    fn stop(&self, reason: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        if state.stopped.is_none() {
+            state.stopped = reason;
+        }
+        if let Some(tx) = state.stop.take() {
+            let _ = tx.send(());
+        }
+    }
+
+    fn stopped(&self) -> Option<IoError> {
+        let state = self.state.lock().unwrap();
+        state.stopped.as_ref().map(|r| IoError::new(IoErrorKind::PermissionDenied, r.clone()))
+    }
+
+    /// Connects remote end for client, transient failures are retried after backoff delay,
+    /// permanent failure stops whole tunnel
+    pub fn connect(
+        &self,
+        tunnel: Tunnel,
+        proxy: Option<Proxy>,
+        header: Option<Vec<u8>>,
+        pool: Option<Pool>,
+    ) -> BoxFuture<ProxyTcpStream> {
+        let supervisor = self.clone();
+        let f = future::loop_fn(0, move |attempt| {
+            let supervisor = supervisor.clone();
+            let name = tunnel.to_string();
+            connect_remote(tunnel.clone(), proxy.as_ref(), header.clone(), pool.as_ref())
+                .then(move |res| -> BoxFuture<Loop<ProxyTcpStream, u32>> {
+                    let e = match res {
+                        Ok(s) => return Box::new(future::ok(Loop::Break(s))),
+                        Err(e) => e,
+                    };
+                    if is_permanent(&e) {
+                        error!("[{}] Permanent failure ({}), stopping tunnel", name, e);
+                        supervisor.stop(Some(e.to_string()));
+                        return Box::new(future::err(e));
+                    }
+                    if attempt >= CLIENT_RETRIES {
+                        return Box::new(future::err(e));
+                    }
+                    let delay = supervisor.failed(Instant::now());
+                    warn!("[{}] Connection to remote end failed ({}), retrying in {:?}", name, e, delay);
+                    Box::new(Delay::new(Instant::now() + delay)
+                        .map_err(IoError::other)
+                        .map(move |_| Loop::Continue(attempt + 1)))
+                })
+        });
+        Box::new(f)
+    }
+}
+
+/// Proxy authentication failure (or missing permission for local port) does not go away by retrying
+fn is_permanent(e: &IoError) -> bool {
+    e.kind() == IoErrorKind::PermissionDenied
+}
+
+/// Random part up to half of delay is dropped, so that tunnels failed at the same time do not retry together
+fn with_jitter(delay: Duration) -> Duration {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+    // mixed, as clock resolution can be coarse
+    let random = (u64::from(nanos).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 54) as f64 / 1024.0;
+    delay - delay.mul_f64(random / 2.0)
+}
+
+/// Runs tunnel and starts it again when it fails (for instance when local port cannot be bound),
+/// connections to remote end are retried, both with the same backoff. Resolves on shutdown,
+/// or to error on permanent failure (proxy authentication failed).
+pub fn supervise(
+    local_addr: IpAddr,
+    tunnel: Tunnel,
+    proxy: Option<Proxy>,
+    shutdown: Shutdown,
+    backoff: Backoff,
+) -> BoxFuture<()> {
+    let (stop_tx, stop_rx) = oneshot::channel();
+    let stop_rx = stop_rx.shared();
+    let supervisor = Supervisor::new(backoff, stop_tx);
+    let f = future::lazy(move || {
+        // listener is stopped by the same signal on shutdown and on permanent failure
+        let s = supervisor.clone();
+        tokio::spawn(shutdown.then(move |_| {
+            s.stop(None);
+            Ok(())
+        }));
+        future::loop_fn((), move |_| {
+            let supervisor = supervisor.clone();
+            let name = tunnel.to_string();
+            serve(local_addr, tunnel.clone(), proxy.clone(), stop_rx.clone(), Some(supervisor.clone()))
+                .then(move |res| -> BoxFuture<Loop<(), ()>> {
+                    if let Some(e) = supervisor.stopped() {
+                        return Box::new(future::err(e));
+                    }
+                    match res {
+                        Ok(()) => Box::new(future::ok(Loop::Break(()))),
+                        Err(ref e) if is_permanent(e) => Box::new(future::err(res.unwrap_err())),
+                        Err(e) => {
+                            let delay = supervisor.failed(Instant::now());
+                            warn!("[{}] Tunnel failed ({}), restarting in {:?}", name, e, delay);
+                            Box::new(Delay::new(Instant::now() + delay)
+                                .map_err(IoError::other)
+                                .map(|_| Loop::Continue(())))
+                        }
+                    }
+                })
+        })
+    });
+    Box::new(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    fn supervisor(backoff: Backoff) -> (Supervisor, oneshot::Receiver<()>) {
+        let (tx, rx) = oneshot::channel();
+        (Supervisor::new(backoff, tx), rx)
+    }
+
+    #[test]
+    fn test_backoff() {
+        let second = Duration::from_secs(1);
+        let (s, _) = supervisor(Backoff { initial: second, max: second * 4, healthy: second * 10 });
+        let now = Instant::now();
+        let delays: Vec<_> = (0..4).map(|i| s.failed(now + second * i)).collect();
+        for (d, expected) in delays.iter().zip(&[1, 2, 4, 4]) {
+            let expected = second * *expected;
+            assert!(*d <= expected && *d >= expected / 2, "{:?} not within jitter of {:?}", d, expected);
+        }
+        // healthy period after last failure resets delay
+        assert!(s.failed(now + second * 13) <= second);
+    }
+
+    #[test]
+    fn test_connect_retries() {
+        // nothing listens there
+        let port = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let tunnel = Tunnel { remote_host: "127.0.0.1".into(), remote_port: port, ..Default::default() };
+        let millis = Duration::from_millis(1);
+        let (s, _) = supervisor(Backoff { initial: millis, max: millis * 100, healthy: Duration::from_secs(60) });
+        let mut rt = ::tokio::runtime::current_thread::Runtime::new().unwrap();
+        assert!(rt.block_on(s.connect(tunnel, None, None, None)).is_err());
+        assert_eq!(s.state.lock().unwrap().delay, millis * 2u32.pow(CLIENT_RETRIES));
+        assert!(s.stopped().is_none());
+    }
+
+    #[test]
+    fn test_permanent_failure() {
+        let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = Proxy { host: "127.0.0.1".into(), port: listener.local_addr().unwrap().port(), ..Default::default() };
+        ::std::thread::spawn(move || {
+            let (mut s, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = s.read(&mut buf).unwrap();
+            s.write_all(b"HTTP/1.1 407 Auth\r\nProxy-Authenticate: Basic realm=\"x\"\r\n\r\n").unwrap();
+        });
+        let tunnel = Tunnel { remote_host: "example.com".into(), remote_port: 80, ..Default::default() };
+        let (s, stop) = supervisor(Backoff::default());
+        let mut rt = ::tokio::runtime::current_thread::Runtime::new().unwrap();
+        let e = rt.block_on(s.connect(tunnel, Some(proxy), None, None)).unwrap_err();
+        assert_eq!(e.kind(), IoErrorKind::PermissionDenied);
+        assert!(s.stopped().is_some());
+        assert!(rt.block_on(stop).is_ok());
+    }
+}
@@ -0,0 +1,165 @@
+use config::{Proxy, Tunnel};
+use tokio_io::IoFuture;
+#[cfg(feature = "tracing")]
+use futures::{Async, Future, Poll};
+#[cfg(feature = "tracing")]
+use std::io::Error as IoError;
+
+/// Span of connect or proxy handshake for tracing subscribers. Without `tracing` feature
+/// it is empty and only log messages are emitted.
+#[cfg(feature = "tracing")]
+pub struct Span(::tracing::Span);
+
+#[cfg(not(feature = "tracing"))]
+pub struct Span;
+
+/// Span of whole connect to remote end, including fallbacks, retries and remote TLS
+#[cfg(feature = "tracing")]
+pub fn connect_span(id: u64, addr: &Tunnel, proxy: Option<&Proxy>) -> Span {
+    let span = ::tracing::info_span!(
+        "connect",
+        id,
+        proxy = ::tracing::field::Empty,
+        target_host = %addr.remote_host,
+        target_port = addr.remote_port,
+        outcome = ::tracing::field::Empty,
+        error = ::tracing::field::Empty,
+    );
+    if let Some(p) = proxy {
+        span.record("proxy", ::tracing::field::display(format_args!("{}:{}", p.host, p.port)));
+    }
+    Span(span)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub fn connect_span(_id: u64, _addr: &Tunnel, _proxy: Option<&Proxy>) -> Span {
+    Span
+}
+
+/// Span of handshake with one proxy (hop of proxy chain), which connects addr
+#[cfg(feature = "tracing")]
+pub fn handshake_span(id: u64, addr: &Tunnel, proxy: &Proxy) -> Span {
+    Span(::tracing::info_span!(
+        "handshake",
+        id,
+        proxy = %format_args!("{}:{}", proxy.host, proxy.port),
+        kind = ?proxy.kind,
+        target_host = %addr.remote_host,
+        target_port = addr.remote_port,
+        outcome = ::tracing::field::Empty,
+        error = ::tracing::field::Empty,
+    ))
+}
+
+#[cfg(not(feature = "tracing"))]
+pub fn handshake_span(_id: u64, _addr: &Tunnel, _proxy: &Proxy) -> Span {
+    Span
+}
+
+/// Enters span whenever future is polled, so that nested spans and log messages (with
+/// tracing-log) belong to it, and records outcome and error, when future completes
+#[cfg(feature = "tracing")]
+pub fn instrument<T: Send + 'static>(f: IoFuture<T>, span: Span) -> IoFuture<T> {
+    Box::new(Instrumented { inner: f, span: span.0 })
+}
+
+#[cfg(not(feature = "tracing"))]
+pub fn instrument<T: Send + 'static>(f: IoFuture<T>, _span: Span) -> IoFuture<T> {
+    f
+}
+
+#[cfg(feature = "tracing")]
+struct Instrumented<T> {
+    inner: IoFuture<T>,
+    span: ::tracing::Span,
+}
+
+#[cfg(feature = "tracing")]
+impl<T> Future for Instrumented<T> {
+    type Item = T;
+    type Error = IoError;
+
+    fn poll(&mut self) -> Poll<T, IoError> {
+        let _entered = self.span.enter();
+        match self.inner.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(v)) => {
+                self.span.record("outcome", "ok");
+                Ok(Async::Ready(v))
+            }
+            Err(e) => {
+                self.span.record("outcome", "failed");
+                self.span.record("error", ::tracing::field::display(&e));
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use super::*;
+    use futures::future;
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata};
+
+    /// Name of span and its recorded fields
+    type Recorded = (String, Vec<(String, String)>);
+
+    /// Collects spans with their recorded fields
+    #[derive(Clone, Default)]
+    struct Collector(Arc<Mutex<Vec<Recorded>>>);
+
+    impl Visit for Collector {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            let mut spans = self.0.lock().unwrap();
+            spans.last_mut().unwrap().1.push((field.name().to_owned(), format!("{:?}", value)));
+        }
+    }
+
+    impl ::tracing::Subscriber for Collector {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+        fn new_span(&self, span: &Attributes) -> Id {
+            self.0.lock().unwrap().push((span.metadata().name().to_owned(), vec![]));
+            span.record(&mut self.clone());
+            Id::from_u64(self.0.lock().unwrap().len() as u64)
+        }
+        fn record(&self, _: &Id, values: &Record) {
+            values.record(&mut self.clone());
+        }
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, _: &Event) {}
+        fn enter(&self, _: &Id) {}
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn test_instrument() {
+        let collector = Collector::default();
+        let addr = Tunnel { remote_host: "imap.example.com".into(), remote_port: 993, ..Default::default() };
+        let proxy = Proxy { host: "proxy".into(), port: 3128, ..Default::default() };
+        ::tracing::subscriber::with_default(collector.clone(), || {
+            let f: IoFuture<()> = Box::new(future::ok(()));
+            instrument(f, connect_span(1, &addr, Some(&proxy))).wait().unwrap();
+            let f: IoFuture<()> = Box::new(future::err(IoError::other("refused")));
+            instrument(f, handshake_span(2, &addr, &proxy)).wait().unwrap_err();
+        });
+        let spans = collector.0.lock().unwrap();
+        let field = |i: usize, name: &str| {
+            spans[i].1.iter().find(|f| f.0 == name).map(|f| f.1.clone())
+        };
+        assert_eq!(spans[0].0, "connect");
+        assert_eq!(field(0, "proxy"), Some("proxy:3128".into()));
+        assert_eq!(field(0, "target_host"), Some("imap.example.com".into()));
+        assert_eq!(field(0, "target_port"), Some("993".into()));
+        assert_eq!(field(0, "outcome"), Some("\"ok\"".into()));
+        assert_eq!(spans[1].0, "handshake");
+        assert_eq!(field(1, "outcome"), Some("\"failed\"".into()));
+        assert_eq!(field(1, "error"), Some("refused".into()));
+    }
+}